@@ -0,0 +1,15 @@
+/* STM32F723IEK6 boot stage
+ *
+ * Flash layout:
+ * Sector 0 (0x08000000): boot stage
+ * Sectors 1-4 (0x08004000): stored target image for standalone programming
+ * Sector 5 (0x08020000): firmware slot A
+ * Sector 6 (0x08040000): firmware slot B
+ * Sector 7 (0x08060000): persistent configuration
+ */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 16k
+  /* Top 16 bytes of RAM are shared with the firmware */
+  RAM : ORIGIN = 0x20000000, LENGTH = 256k - 16
+}
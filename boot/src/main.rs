@@ -0,0 +1,25 @@
+//! Boot stage for hs-probe-firmware.
+//!
+//! Lives in the first flash sector and starts the newest valid firmware image
+//! from one of the two application slots, see `hs_probe_bsp::image`.
+
+#![no_std]
+#![no_main]
+
+use cortex_m_rt::entry;
+use hs_probe_bsp as bsp;
+
+#[entry]
+fn main() -> ! {
+    // Jump to the system bootloader if the firmware requested it
+    bsp::bootload::check();
+
+    let flash = bsp::flash::Flash::new(stm32ral::flash::FLASH::take().unwrap());
+    bsp::bootload::boot(&flash)
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    // Nothing useful can be done without firmware, so wait for the system bootloader
+    bsp::bootload::bootload()
+}
@@ -3,9 +3,17 @@ use std::fs;
 use std::path::PathBuf;
 
 fn main() {
+    // Link for the application slot selected by the `slot-b` feature
+    let memory = if env::var_os("CARGO_FEATURE_SLOT_B").is_some() {
+        "memory-b.x"
+    } else {
+        "memory-a.x"
+    };
+
     // Put the linker script somewhere the linker can find it
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    fs::copy("memory.x", out_dir.join("memory.x")).unwrap();
+    fs::copy(memory, out_dir.join("memory.x")).unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-a.x");
+    println!("cargo:rerun-if-changed=memory-b.x");
 }
@@ -0,0 +1,8 @@
+/* STM32F723IEK6, firmware in slot A */
+MEMORY
+{
+  /* Slot A (sector 5), after the image header block */
+  FLASH : ORIGIN = 0x08020200, LENGTH = 128k - 0x200
+  /* Top 16 bytes of RAM are shared with the boot stage */
+  RAM : ORIGIN = 0x20000000, LENGTH = 256k - 16
+}
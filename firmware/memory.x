@@ -1,6 +0,0 @@
-/* STM32F723IEK6 */
-MEMORY
-{
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512k
-  RAM : ORIGIN = 0x20000000, LENGTH = 256k
-}
@@ -1,25 +1,156 @@
+use crate::config::Config;
 use crate::dap::DAPVersion;
+use crate::rtt;
+use crate::usb::USB;
 use crate::{DAP1_PACKET_SIZE, DAP2_PACKET_SIZE};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::NVIC;
 use hs_probe_bsp as bsp;
-use hs_probe_bsp::rcc::CoreFrequency;
+use hs_probe_bsp::rcc::{ClockConfig, CoreFrequency};
+use stm32ral::Interrupt;
 
 #[allow(clippy::large_enum_variant)]
 pub enum Request {
+    /// The host suspended the bus
     Suspend,
+    /// The host resumed the bus after a suspend
+    Resume,
+    /// The device was reset or deconfigured by the host
+    Reset,
     DAP1Command(([u8; DAP1_PACKET_SIZE as usize], usize)),
     DAP2Command(([u8; DAP2_PACKET_SIZE as usize], usize)),
 }
 
+/// Resources owned by the USB interrupt handler once the App is set up.
+struct UsbContext {
+    usb: &'static mut USB,
+    swo: &'static bsp::uart::UART<'static>,
+    swo_buf: [u8; DAP2_PACKET_SIZE as usize],
+}
+
+// Only accessed from the USB interrupt handler, or from thread mode inside a
+// critical section.
+static mut USB_CONTEXT: Option<UsbContext> = None;
+
+/// The next DAP command for the thread mode executor.
+static COMMAND: Mutex<RefCell<Option<Request>>> = Mutex::new(RefCell::new(None));
+
+/// The most recent USB state change not yet handled by the executor.
+static EVENT: Mutex<RefCell<Option<Request>>> = Mutex::new(RefCell::new(None));
+
+/// Whether SWO data is streamed over the trace endpoint.
+static SWO_STREAMING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the serial port carries RTT data, rather than discarding input.
+static RTT_BRIDGE: AtomicBool = AtomicBool::new(false);
+
+/// Handle a USB interrupt, queueing new requests for `App::poll`.
+///
+/// Call from the OTG_HS interrupt handler.
+pub fn usb_interrupt() {
+    let ctx = match unsafe { USB_CONTEXT.as_mut() } {
+        Some(ctx) => ctx,
+        None => return,
+    };
+
+    // Leave new commands in their endpoints until the executor is ready
+    let ready = interrupt::free(|cs| COMMAND.borrow(cs).borrow().is_none());
+    match ctx.usb.interrupt(ready, !RTT_BRIDGE.load(Ordering::SeqCst)) {
+        Some(req @ Request::DAP1Command(_)) | Some(req @ Request::DAP2Command(_)) => {
+            interrupt::free(|cs| COMMAND.borrow(cs).replace(Some(req)));
+        }
+        Some(req) => {
+            interrupt::free(|cs| EVENT.borrow(cs).replace(Some(req)));
+        }
+        None => (),
+    }
+
+    if SWO_STREAMING.load(Ordering::SeqCst) && !ctx.usb.dap2_swo_is_busy() {
+        // Send new UART data when streaming is enabled and
+        // the SWO endpoint is ready to transmit more data.
+        let len = ctx.swo.read(&mut ctx.swo_buf);
+
+        if len > 0 {
+            ctx.usb.dap2_stream_swo(&ctx.swo_buf[0..len]);
+        }
+    }
+}
+
+/// Handle an SWO UART or DMA interrupt by scheduling the USB interrupt handler
+/// to stream out the new data.
+///
+/// Call from the USART1 and DMA2_Stream5 interrupt handlers.
+pub fn swo_interrupt() {
+    if let Some(ctx) = unsafe { USB_CONTEXT.as_ref() } {
+        ctx.swo.clear_interrupts();
+    }
+    NVIC::pend(Interrupt::OTG_HS);
+}
+
+/// Run `f` with exclusive access to the USB stack from thread mode.
+fn with_usb<R>(f: impl FnOnce(&mut USB) -> R) -> R {
+    interrupt::free(|_| {
+        let ctx = unsafe { USB_CONTEXT.as_mut() }.expect("USB not set up");
+        f(&mut *ctx.usb)
+    })
+}
+
+/// Wait until the DAPv2 reply endpoint can take another packet.
+///
+/// Returns false if a USB state change arrives first.
+fn wait_dap2_reply() -> bool {
+    loop {
+        bsp::bootload::feed_watchdog();
+        if interrupt::free(|cs| EVENT.borrow(cs).borrow().is_some()) {
+            return false;
+        }
+        if !with_usb(|usb| usb.dap2_reply_is_busy()) {
+            return true;
+        }
+    }
+}
+
+/// Time between toggles of the red LED after programming the stored image failed
+const FAILURE_BLINK_US: u32 = 250_000;
+
+/// Probe state saved while the USB bus is suspended.
+struct SuspendedState {
+    tvcc_en: bool,
+    t5v_en: bool,
+    leds: [bool; 3],
+}
+
 pub struct App<'a> {
     rcc: &'a bsp::rcc::RCC,
     dma: &'a bsp::dma::DMA,
     pins: &'a bsp::gpio::Pins<'a>,
     swd_spi: &'a bsp::spi::SPI,
     jtag_spi: &'a bsp::spi::SPI,
-    usb: &'a mut crate::usb::USB,
+    usb: Option<&'static mut USB>,
+    swo: &'static bsp::uart::UART<'static>,
     dap: &'a mut crate::dap::DAP<'a>,
     delay: &'a bsp::delay::Delay,
+    timer: &'a bsp::timer::Timer,
+    flash: &'static bsp::flash::Flash,
+    boot_confirmed: bool,
+    clocks: ClockConfig,
+    suspended: Option<SuspendedState>,
     resp_buf: [u8; DAP2_PACKET_SIZE as usize],
+    /// Timer value when background tasks were last considered
+    background_last: u32,
+    /// Microseconds elapsed since the watched word was last read
+    watch_elapsed: u32,
+    /// Microseconds elapsed since RTT buffers were last polled
+    rtt_elapsed: u32,
+    /// Microseconds elapsed since target memory was last sampled
+    sample_elapsed: u32,
+    /// Microseconds elapsed since the target PC was last sampled
+    pc_sample_elapsed: u32,
+    /// Timer value when the red LED last toggled, while blinking to show
+    /// that programming the stored image failed
+    failure_blink: Option<u32>,
 }
 
 impl<'a> App<'a> {
@@ -30,9 +161,12 @@ impl<'a> App<'a> {
         pins: &'a bsp::gpio::Pins<'a>,
         swd_spi: &'a bsp::spi::SPI,
         jtag_spi: &'a bsp::spi::SPI,
-        usb: &'a mut crate::usb::USB,
+        usb: &'static mut USB,
+        swo: &'static bsp::uart::UART<'static>,
         dap: &'a mut crate::dap::DAP<'a>,
         delay: &'a bsp::delay::Delay,
+        timer: &'a bsp::timer::Timer,
+        flash: &'static bsp::flash::Flash,
     ) -> Self {
         App {
             rcc,
@@ -40,23 +174,37 @@ impl<'a> App<'a> {
             pins,
             swd_spi,
             jtag_spi,
-            usb,
+            usb: Some(usb),
+            swo,
             dap,
             delay,
+            timer,
+            flash,
+            boot_confirmed: false,
+            clocks: CoreFrequency::F72MHz.into(),
+            suspended: None,
             resp_buf: [0; DAP2_PACKET_SIZE as usize],
+            background_last: 0,
+            watch_elapsed: 0,
+            rtt_elapsed: 0,
+            sample_elapsed: 0,
+            pc_sample_elapsed: 0,
+            failure_blink: None,
         }
     }
 
     /// Unsafety: this function should be called from the main context.
     /// No other contexts should be active at the same time.
-    pub unsafe fn setup(&mut self, serial: &'static str) {
+    pub unsafe fn setup(&mut self, config: &'static Config) {
         // Configure system clock
-        #[cfg(not(feature = "turbo"))]
-        let clocks = self.rcc.setup(CoreFrequency::F72MHz);
-        #[cfg(feature = "turbo")]
-        let clocks = self.rcc.setup(CoreFrequency::F216MHz);
+        let frequency = config.core_frequency();
+        let clocks = self.rcc.setup(frequency);
+        self.clocks = frequency.into();
+        self.dap.set_core_frequency(frequency);
 
         self.delay.set_sysclk(&clocks);
+        self.timer.setup(&clocks);
+        self.swo.set_base_clock(&clocks);
 
         // Configure DMA for SPI1, SPI2, USART1 and USART2 transfers
         self.dma.setup();
@@ -71,27 +219,124 @@ impl<'a> App<'a> {
         self.jtag_spi.set_base_clock(&clocks);
         self.jtag_spi.disable();
 
+        // Program the target from an image stored on the probe, if it is set to
+        // be programmed on boot, before connecting to any host
+        self.pins.led_blue.set_low();
+        let programmed = self.dap.program_stored_image();
+        self.pins.led_blue.set_high();
+
         // Configure USB peripheral and connect to host
-        self.usb.setup(&clocks, serial);
+        let usb = self.usb.take().expect("App already set up");
+        usb.setup(&clocks, config, self.flash);
+
+        // Hand the USB stack over to its interrupt handler
+        USB_CONTEXT = Some(UsbContext {
+            usb,
+            swo: self.swo,
+            swo_buf: [0; DAP2_PACKET_SIZE as usize],
+        });
+        NVIC::unmask(Interrupt::OTG_HS);
+        NVIC::unmask(Interrupt::USART1);
+        NVIC::unmask(Interrupt::DMA2_Stream5);
+        NVIC::unmask(Interrupt::DMA1_Stream3);
 
-        self.pins.led_red.set_low();
+        // Wake up regularly to feed the watchdog while idle
+        self.delay.enable_wrap_interrupt();
+
+        // Show whether programming the stored image passed, until the host resets us.
+        // Red stays on when idle, so a failure blinks it instead.
+        match programmed {
+            Some(true) => {
+                self.pins.led_green.set_low();
+            }
+            Some(false) => {
+                self.pins.led_red.set_low();
+                self.failure_blink = Some(self.timer.now_us());
+            }
+            None => {
+                self.pins.led_red.set_low();
+            }
+        }
         // self.pins.t5v_en.set_high();
     }
 
     pub fn poll(&mut self) {
-        if let Some(req) = self.usb.interrupt() {
-            self.process_request(req);
+        // Reaching the main loop means this firmware image boots successfully,
+        // so tell the boot stage not to roll back to the previous image.
+        if !self.boot_confirmed {
+            bsp::bootload::confirm(self.flash);
+            self.boot_confirmed = true;
         }
+        bsp::bootload::feed_watchdog();
 
-        if self.dap.is_swo_streaming() && !self.usb.dap2_swo_is_busy() {
-            // Poll for new UART data when streaming is enabled and
-            // the SWO endpoint is ready to transmit more data.
-            let len = self.dap.read_swo(&mut self.resp_buf);
+        // Handle USB state changes before any queued command, sleeping
+        // until the next interrupt if there is nothing to do. WFI inside the
+        // critical section still wakes on a pending interrupt, which is then
+        // handled once the critical section ends. While running background
+        // tasks such as watching a target word we keep running instead.
+        let background = self.suspended.is_none() && self.dap.has_background_tasks();
+        let (req, dfu) = interrupt::free(|cs| {
+            let dfu = with_usb(|usb| usb.dfu_operation());
+            let req = EVENT
+                .borrow(cs)
+                .replace(None)
+                .or_else(|| COMMAND.borrow(cs).replace(None));
+            if req.is_none() && dfu.is_none() && !background {
+                cortex_m::asm::wfi();
+            }
+            (req, dfu)
+        });
+
+        // Erase and program firmware slots here rather than in the USB
+        // interrupt. The single flash bank still stalls all code, the USB
+        // interrupt included, while a sector erases or a word programs, but
+        // the interrupt can run between those steps instead of being held
+        // for the whole operation
+        if let Some(operation) = dfu {
+            let status = operation.run();
+            with_usb(|usb| usb.dfu_complete(status));
+        }
 
-            if len > 0 {
-                self.usb.dap2_stream_swo(&self.resp_buf[0..len]);
+        if req.is_none() && background {
+            self.poll_background();
+        }
+
+        // Blink the failure indication, unless the LEDs are off for a suspend
+        if let (Some(last), None) = (self.failure_blink, &self.suspended) {
+            let now = self.timer.now_us();
+            if now.wrapping_sub(last) >= FAILURE_BLINK_US {
+                self.pins.led_red.toggle();
+                self.failure_blink = Some(now);
             }
         }
+
+        // Woken at least every SysTick wrap, so stalled bulk writes are noticed
+        self.dap.check_data_timeout();
+
+        if let Some(req) = req {
+            self.process_request(req);
+
+            // Apply any clock tree change requested by the DAP command
+            if let Some(clocks) = self.dap.take_clock_request() {
+                // Relocking the PLL stalls USB, so let the response go out first
+                if !clocks.same_pll(&self.clocks) {
+                    wait_dap2_reply();
+                }
+                self.set_clocks(clocks);
+                self.dap.update_clocks(clocks);
+            }
+
+            SWO_STREAMING.store(self.dap.is_swo_streaming(), Ordering::SeqCst);
+            RTT_BRIDGE.store(self.dap.rtt_active(), Ordering::SeqCst);
+
+            // Let the USB interrupt read any command waiting in its endpoint
+            NVIC::pend(Interrupt::OTG_HS);
+        }
+
+        // Count background task periods from when they are started
+        if !background {
+            self.background_last = self.timer.now_us();
+        }
     }
 
     fn process_request(&mut self, req: Request) {
@@ -104,7 +349,8 @@ impl<'a> App<'a> {
                 );
 
                 if len > 0 {
-                    self.usb.dap1_reply(&self.resp_buf[..len]);
+                    let resp = &self.resp_buf[..len];
+                    with_usb(|usb| usb.dap1_reply(resp));
                 }
             }
             Request::DAP2Command((report, n)) => {
@@ -113,16 +359,199 @@ impl<'a> App<'a> {
                         .process_command(&report[..n], &mut self.resp_buf, DAPVersion::V2);
 
                 if len > 0 {
-                    self.usb.dap2_reply(&self.resp_buf[..len]);
+                    let resp = &self.resp_buf[..len];
+                    with_usb(|usb| usb.dap2_reply(resp));
+                }
+
+                // Bulk memory reads reply with further packets
+                while self.dap.has_continuation() {
+                    if !wait_dap2_reply() {
+                        self.dap.abort_continuation();
+                        break;
+                    }
+                    let len = self.dap.process_continuation(&mut self.resp_buf);
+                    let resp = &self.resp_buf[..len];
+                    with_usb(|usb| usb.dap2_reply(resp));
                 }
             }
-            Request::Suspend => {
-                self.pins.high_impedance_mode();
-                self.pins.led_blue.set_high();
+            Request::Suspend => self.suspend(),
+            Request::Resume => self.resume(),
+            Request::Reset => {
+                self.resume();
+                self.dap.disconnect();
+                self.apply_core_clocks();
                 self.pins.tvcc_en.set_low();
-                self.swd_spi.disable();
-                self.jtag_spi.disable();
+                self.pins.t5v_en.set_low();
+                self.failure_blink = None;
+                self.pins.led_red.set_low();
+                self.pins.led_green.set_high();
+                self.pins.led_blue.set_high();
+            }
+        }
+    }
+
+    /// Run the background tasks which are due: reading the watched target
+    /// word, bridging RTT to the serial port, sampling target memory and
+    /// sampling the target PC.
+    fn poll_background(&mut self) {
+        // The timer keeps counting through long commands and clock changes
+        let now = self.timer.now_us();
+        let elapsed = now.wrapping_sub(self.background_last);
+        self.background_last = now;
+
+        if let Some(interval_ms) = self.dap.watch_interval() {
+            self.watch_elapsed = self.watch_elapsed.saturating_add(elapsed);
+            if self.watch_elapsed >= interval_ms.saturating_mul(1000) {
+                self.poll_watch();
+            }
+        }
+
+        if self.dap.rtt_active() {
+            self.rtt_elapsed = self.rtt_elapsed.saturating_add(elapsed);
+            if self.rtt_elapsed >= rtt::POLL_INTERVAL_MS * 1000 {
+                self.rtt_elapsed = 0;
+                self.dap.poll_rtt(
+                    &mut self.resp_buf,
+                    |data| with_usb(|usb| usb.serial_write(data)),
+                    |buf| with_usb(|usb| usb.serial_read(buf)),
+                );
             }
         }
+
+        if let Some(period) = self.dap.sample_period() {
+            let period = core::cmp::max(period, 1);
+            self.sample_elapsed = self.sample_elapsed.saturating_add(elapsed);
+            if self.sample_elapsed >= period {
+                // Periods we fell behind by are reported as dropped samples
+                let missed = self.sample_elapsed / period - 1;
+                self.sample_elapsed %= period;
+                self.dap.sample(now, missed);
+            }
+        } else {
+            self.sample_elapsed = 0;
+        }
+
+        if let Some(rate_hz) = self.dap.pc_sample_rate() {
+            let period = core::cmp::max(1_000_000 / rate_hz, 1);
+            self.pc_sample_elapsed = self.pc_sample_elapsed.saturating_add(elapsed);
+            if self.pc_sample_elapsed >= period {
+                // Periods we fell behind by are reported as dropped samples
+                let missed = self.pc_sample_elapsed / period - 1;
+                self.pc_sample_elapsed %= period;
+                self.dap.sample_pc(missed);
+            }
+        } else {
+            self.pc_sample_elapsed = 0;
+        }
+
+        self.send_stream();
+    }
+
+    /// Send collected samples over the trace endpoint when it is free and
+    /// not streaming SWO.
+    fn send_stream(&mut self) {
+        if SWO_STREAMING.load(Ordering::SeqCst) || with_usb(|usb| usb.dap2_swo_is_busy()) {
+            return;
+        }
+        let len = match self.dap.take_samples(&mut self.resp_buf) {
+            0 => self.dap.take_pc_samples(&mut self.resp_buf),
+            len => len,
+        };
+        if len > 0 {
+            let packet = &self.resp_buf[..len];
+            with_usb(|usb| usb.dap2_stream_swo(packet));
+        }
+    }
+
+    /// Read the watched target word, sending any event over the trace
+    /// endpoint while it is not streaming SWO.
+    fn poll_watch(&mut self) {
+        if SWO_STREAMING.load(Ordering::SeqCst) || with_usb(|usb| usb.dap2_swo_is_busy()) {
+            return;
+        }
+        self.watch_elapsed = 0;
+
+        let len = self.dap.poll_watch(&mut self.resp_buf);
+        if len > 0 {
+            let event = &self.resp_buf[..len];
+            with_usb(|usb| usb.dap2_stream_swo(event));
+        }
+    }
+
+    /// Power down the target interface and slow the core while suspended.
+    fn suspend(&mut self) {
+        if self.suspended.is_some() {
+            return;
+        }
+
+        self.suspended = Some(SuspendedState {
+            tvcc_en: self.pins.tvcc_en.is_high(),
+            t5v_en: self.pins.t5v_en.is_high(),
+            leds: [
+                self.pins.led_red.is_high(),
+                self.pins.led_green.is_high(),
+                self.pins.led_blue.is_high(),
+            ],
+        });
+
+        self.dap.suspend();
+        self.pins.tvcc_en.set_low();
+        self.pins.t5v_en.set_low();
+        self.pins.led_red.set_high();
+        self.pins.led_green.set_high();
+        self.pins.led_blue.set_high();
+
+        self.set_clocks(CoreFrequency::F48MHz.into());
+    }
+
+    /// Restore the state saved by `suspend`, if suspended.
+    fn resume(&mut self) {
+        let state = match self.suspended.take() {
+            Some(state) => state,
+            None => return,
+        };
+
+        self.apply_core_clocks();
+
+        self.pins.tvcc_en.set_bool(state.tvcc_en);
+        self.pins.t5v_en.set_bool(state.t5v_en);
+        self.pins.led_red.set_bool(state.leds[0]);
+        self.pins.led_green.set_bool(state.leds[1]);
+        self.pins.led_blue.set_bool(state.leds[2]);
+
+        self.dap.resume();
+    }
+
+    /// Apply the core frequency selected by the DAP, relocking the PLL if it
+    /// changed, such as after a suspend slowed the core.
+    fn apply_core_clocks(&mut self) {
+        let clocks = self.dap.core_clocks();
+        self.set_clocks(clocks);
+        self.dap.update_clocks(clocks);
+    }
+
+    /// Change the clock tree configuration and update everything derived from it.
+    ///
+    /// Changing only the APB prescalers keeps the system clock running, while
+    /// relocking the PLL runs it from HSE meanwhile, too slow for USB HS
+    /// transfers. The USB interrupt is masked until it is done, and the host
+    /// retries any transfer it NAKs meanwhile.
+    fn set_clocks(&mut self, config: ClockConfig) {
+        if config == self.clocks {
+            return;
+        }
+
+        // Keep the USB interrupt from running while clocks are changing
+        let clocks = if config.same_pll(&self.clocks) {
+            interrupt::free(|_| self.rcc.set_prescalers(config))
+        } else {
+            interrupt::free(|_| unsafe { self.rcc.configure(config) })
+        };
+        self.delay.set_sysclk(&clocks);
+        self.timer.set_base_clock(&clocks);
+        self.swd_spi.set_base_clock(&clocks);
+        self.jtag_spi.set_base_clock(&clocks);
+        self.swo.set_base_clock(&clocks);
+        self.clocks = config;
     }
 }
@@ -0,0 +1,311 @@
+use crate::bsp::flash::{self, Flash};
+use crate::bsp::rcc::CoreFrequency;
+use core::convert::TryInto;
+use num_enum::TryFromPrimitive;
+
+/// Persistent configuration lives in the final 128kB flash sector,
+/// which is excluded from the FLASH region in memory.x.
+const CONFIG_SECTOR: u8 = 7;
+const CONFIG_MAGIC: u32 = 0x4643_5348; // "HSCF"
+const CONFIG_VERSION: u8 = 1;
+
+pub const DEFAULT_VID: u16 = 0x1209;
+pub const DEFAULT_PID: u16 = 0x4853;
+pub const DEFAULT_PRODUCT: &str = "HS-Probe with CMSIS-DAP Support";
+pub const DEFAULT_CORE_FREQUENCY: CoreFrequency = CoreFrequency::F72MHz;
+
+/// Host tools detect CMSIS-DAP probes by looking for this in the product string.
+const REQUIRED_PRODUCT_SUBSTRING: &str = "CMSIS-DAP";
+
+pub const SERIAL_MAX_LEN: usize = 32;
+pub const PRODUCT_MAX_LEN: usize = 64;
+
+// Stored layout:
+// 0..4: magic
+// 4: version
+// 5: serial length, 0 to use the default serial
+// 6: product length, 0 to use the default product
+// 7: core frequency in MHz, 0 to use the default
+// 8..10: VID
+// 10..12: PID
+// 12..44: serial
+// 44..108: product
+// 108..112: checksum of bytes 0..108
+const SERIAL_OFFSET: usize = 12;
+const PRODUCT_OFFSET: usize = SERIAL_OFFSET + SERIAL_MAX_LEN;
+const CHECKSUM_OFFSET: usize = PRODUCT_OFFSET + PRODUCT_MAX_LEN;
+const STORED_LEN: usize = CHECKSUM_OFFSET + 4;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    InvalidLength,
+    InvalidCharacter,
+    MissingCMSISDAP,
+    InvalidID,
+    InvalidFrequency,
+    Flash(flash::Error),
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ConfigKey {
+    SerialNumber = 0,
+    Product = 1,
+    VendorID = 2,
+    ProductID = 3,
+    CoreFrequency = 4,
+}
+
+/// User-configurable probe settings, persisted in flash.
+///
+/// Fields left unset in flash fall back to the built-in defaults.
+#[derive(Clone)]
+pub struct Config {
+    default_serial: &'static str,
+    vid: u16,
+    pid: u16,
+    serial_len: usize,
+    serial: [u8; SERIAL_MAX_LEN],
+    product_len: usize,
+    product: [u8; PRODUCT_MAX_LEN],
+    core_frequency: CoreFrequency,
+}
+
+impl Config {
+    /// Create a configuration with all settings at their defaults.
+    pub fn new(default_serial: &'static str) -> Self {
+        Config {
+            default_serial,
+            vid: DEFAULT_VID,
+            pid: DEFAULT_PID,
+            serial_len: 0,
+            serial: [0; SERIAL_MAX_LEN],
+            product_len: 0,
+            product: [0; PRODUCT_MAX_LEN],
+            core_frequency: DEFAULT_CORE_FREQUENCY,
+        }
+    }
+
+    /// Load configuration from flash.
+    ///
+    /// If flash does not contain a valid configuration, or any stored
+    /// setting fails validation, that setting takes its default value.
+    pub fn load(default_serial: &'static str) -> Self {
+        let mut config = Self::new(default_serial);
+
+        let stored = Flash::read(Self::address(), STORED_LEN);
+        let magic = u32::from_le_bytes(stored[0..4].try_into().unwrap());
+        let checksum = u32::from_le_bytes(stored[CHECKSUM_OFFSET..].try_into().unwrap());
+        if magic != CONFIG_MAGIC
+            || stored[4] != CONFIG_VERSION
+            || checksum != Self::checksum(&stored[..CHECKSUM_OFFSET])
+        {
+            return config;
+        }
+
+        let vid = u16::from_le_bytes(stored[8..10].try_into().unwrap());
+        let pid = u16::from_le_bytes(stored[10..12].try_into().unwrap());
+        config.set_vid(vid).ok();
+        config.set_pid(pid).ok();
+        config.set_core_frequency(stored[7]).ok();
+
+        let serial_len = stored[5] as usize;
+        if serial_len > 0 && serial_len <= SERIAL_MAX_LEN {
+            config
+                .set_serial(&stored[SERIAL_OFFSET..SERIAL_OFFSET + serial_len])
+                .ok();
+        }
+
+        let product_len = stored[6] as usize;
+        if product_len > 0 && product_len <= PRODUCT_MAX_LEN {
+            config
+                .set_product(&stored[PRODUCT_OFFSET..PRODUCT_OFFSET + product_len])
+                .ok();
+        }
+
+        config
+    }
+
+    /// Write this configuration to flash, replacing any previous configuration.
+    pub fn save(&self, flash: &Flash) -> Result<()> {
+        let mut stored = [0u8; STORED_LEN];
+        stored[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        stored[4] = CONFIG_VERSION;
+        stored[5] = self.serial_len as u8;
+        stored[6] = self.product_len as u8;
+        stored[7] = self.core_frequency.mhz() as u8;
+        stored[8..10].copy_from_slice(&self.vid.to_le_bytes());
+        stored[10..12].copy_from_slice(&self.pid.to_le_bytes());
+        stored[SERIAL_OFFSET..PRODUCT_OFFSET].copy_from_slice(&self.serial);
+        stored[PRODUCT_OFFSET..CHECKSUM_OFFSET].copy_from_slice(&self.product);
+        let checksum = Self::checksum(&stored[..CHECKSUM_OFFSET]);
+        stored[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+
+        flash.erase_sector(CONFIG_SECTOR)?;
+        flash.program(Self::address(), &stored)?;
+        Ok(())
+    }
+
+    pub fn vid(&self) -> u16 {
+        self.vid
+    }
+
+    pub fn pid(&self) -> u16 {
+        self.pid
+    }
+
+    pub fn serial(&self) -> &str {
+        if self.serial_len == 0 {
+            self.default_serial
+        } else {
+            // Contents are checked to be ASCII by set_serial()
+            core::str::from_utf8(&self.serial[..self.serial_len]).unwrap_or(self.default_serial)
+        }
+    }
+
+    pub fn product(&self) -> &str {
+        if self.product_len == 0 {
+            DEFAULT_PRODUCT
+        } else {
+            // Contents are checked to be ASCII by set_product()
+            core::str::from_utf8(&self.product[..self.product_len]).unwrap_or(DEFAULT_PRODUCT)
+        }
+    }
+
+    /// Core frequency to run at after reset
+    pub fn core_frequency(&self) -> CoreFrequency {
+        self.core_frequency
+    }
+
+    /// Read the current value of `key` into `buf`, returning the number of bytes written.
+    pub fn get(&self, key: ConfigKey, buf: &mut [u8]) -> usize {
+        let value: &[u8] = match key {
+            ConfigKey::SerialNumber => self.serial().as_bytes(),
+            ConfigKey::Product => self.product().as_bytes(),
+            ConfigKey::VendorID => return Self::write_u16(self.vid, buf),
+            ConfigKey::ProductID => return Self::write_u16(self.pid, buf),
+            ConfigKey::CoreFrequency => {
+                buf[0] = self.core_frequency.mhz() as u8;
+                return 1;
+            }
+        };
+        let len = core::cmp::min(value.len(), buf.len());
+        buf[..len].copy_from_slice(&value[..len]);
+        len
+    }
+
+    /// Set `key` from `value`. An empty `value` restores the default.
+    pub fn set(&mut self, key: ConfigKey, value: &[u8]) -> Result<()> {
+        match key {
+            ConfigKey::SerialNumber => self.set_serial(value),
+            ConfigKey::Product => self.set_product(value),
+            ConfigKey::VendorID => self.set_vid(Self::read_u16(value)?),
+            ConfigKey::ProductID => self.set_pid(Self::read_u16(value)?),
+            ConfigKey::CoreFrequency => match value {
+                [] => self.set_core_frequency(0),
+                [mhz] => self.set_core_frequency(*mhz),
+                _ => Err(Error::InvalidLength),
+            },
+        }
+    }
+
+    /// Set the USB serial number string. An empty string restores the default.
+    pub fn set_serial(&mut self, serial: &[u8]) -> Result<()> {
+        if serial.len() > SERIAL_MAX_LEN {
+            return Err(Error::InvalidLength);
+        }
+        // Serial numbers are used in paths and command lines, so stick to
+        // characters that never need quoting.
+        let valid = |c: &u8| c.is_ascii_alphanumeric() || b"-_.".contains(c);
+        if !serial.iter().all(valid) {
+            return Err(Error::InvalidCharacter);
+        }
+        self.serial[..serial.len()].copy_from_slice(serial);
+        self.serial[serial.len()..].iter_mut().for_each(|b| *b = 0);
+        self.serial_len = serial.len();
+        Ok(())
+    }
+
+    /// Set the USB product string. An empty string restores the default.
+    ///
+    /// The product string must contain "CMSIS-DAP" for host tools to detect the probe.
+    pub fn set_product(&mut self, product: &[u8]) -> Result<()> {
+        if product.len() > PRODUCT_MAX_LEN {
+            return Err(Error::InvalidLength);
+        }
+        if !product.iter().all(|c| (0x20..0x7F).contains(c)) {
+            return Err(Error::InvalidCharacter);
+        }
+        if !product.is_empty()
+            && !product
+                .windows(REQUIRED_PRODUCT_SUBSTRING.len())
+                .any(|w| w == REQUIRED_PRODUCT_SUBSTRING.as_bytes())
+        {
+            return Err(Error::MissingCMSISDAP);
+        }
+        self.product[..product.len()].copy_from_slice(product);
+        self.product[product.len()..].iter_mut().for_each(|b| *b = 0);
+        self.product_len = product.len();
+        Ok(())
+    }
+
+    /// Set the USB vendor ID. Zero restores the default.
+    pub fn set_vid(&mut self, vid: u16) -> Result<()> {
+        self.vid = match vid {
+            0 => DEFAULT_VID,
+            0xFFFF => return Err(Error::InvalidID),
+            vid => vid,
+        };
+        Ok(())
+    }
+
+    /// Set the USB product ID. Zero restores the default.
+    pub fn set_pid(&mut self, pid: u16) -> Result<()> {
+        self.pid = match pid {
+            0 => DEFAULT_PID,
+            0xFFFF => return Err(Error::InvalidID),
+            pid => pid,
+        };
+        Ok(())
+    }
+
+    /// Set the core frequency in MHz, one of 48, 72 or 216. Zero restores the default.
+    pub fn set_core_frequency(&mut self, mhz: u8) -> Result<()> {
+        self.core_frequency = match mhz {
+            0 => DEFAULT_CORE_FREQUENCY,
+            mhz => CoreFrequency::from_mhz(mhz as u32).ok_or(Error::InvalidFrequency)?,
+        };
+        Ok(())
+    }
+
+    fn address() -> u32 {
+        flash::SECTOR_ADDRESSES[CONFIG_SECTOR as usize]
+    }
+
+    fn read_u16(value: &[u8]) -> Result<u16> {
+        match value.len() {
+            0 => Ok(0),
+            2 => Ok(u16::from_le_bytes(value.try_into().unwrap())),
+            _ => Err(Error::InvalidLength),
+        }
+    }
+
+    fn write_u16(value: u16, buf: &mut [u8]) -> usize {
+        buf[..2].copy_from_slice(&value.to_le_bytes());
+        2
+    }
+
+    /// Simple rotate-and-xor checksum, sufficient to reject erased or torn writes.
+    fn checksum(data: &[u8]) -> u32 {
+        data.iter()
+            .fold(CONFIG_MAGIC, |acc, &b| acc.rotate_left(5) ^ (b as u32))
+    }
+}
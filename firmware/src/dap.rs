@@ -2,8 +2,23 @@
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
 use crate::{
-    bsp::{gpio::Pins, uart::UART},
-    jtag, swd, DAP1_PACKET_SIZE, DAP2_PACKET_SIZE,
+    bsp::{
+        crc::CRC,
+        delay::Delay,
+        flash::Flash,
+        gpio::Pins,
+        rcc::{ClockConfig, CoreFrequency},
+        spi::SPIPrescaler,
+        timer::Timer,
+        uart::UART,
+    },
+    config::{Config, ConfigKey},
+    flashalgo::{self, FlashAlgo, Programmer},
+    jtag,
+    memap::{self, MemAP},
+    rtt::RTT,
+    standalone::{self, StoredImage},
+    swd, DAP1_PACKET_SIZE, DAP2_PACKET_SIZE,
 };
 use core::convert::{TryFrom, TryInto};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -60,6 +75,34 @@ enum Command {
     // DAP_ExecuteCommands = 0x7F,
     // DAP_QueueCommands = 0x7E,
 
+    // Vendor Commands
+    DAP_Vendor_ConfigRead = 0x80,
+    DAP_Vendor_ConfigWrite = 0x81,
+    DAP_Vendor_ClockInfo = 0x82,
+    DAP_Vendor_CoreFrequency = 0x83,
+    DAP_Vendor_SWDSampling = 0x84,
+    DAP_Vendor_SWDCalibrate = 0x85,
+    DAP_Vendor_SWDClockDiscovery = 0x86,
+    DAP_Vendor_SWDSelectTarget = 0x87,
+    DAP_Vendor_SWJSequence = 0x88,
+    DAP_Vendor_SWDRecovery = 0x89,
+    DAP_Vendor_SWDCacheStats = 0x8A,
+    DAP_Vendor_MemoryRead = 0x8B,
+    DAP_Vendor_MemoryWrite = 0x8C,
+    DAP_Vendor_MemoryCRC = 0x8D,
+    DAP_Vendor_MemoryErased = 0x8E,
+    DAP_Vendor_Watch = 0x8F,
+    DAP_Vendor_RTT = 0x90,
+    DAP_Vendor_Sample = 0x91,
+    DAP_Vendor_PCSample = 0x92,
+    DAP_Vendor_FlashAlgo = 0x93,
+    DAP_Vendor_FlashCall = 0x94,
+    DAP_Vendor_FlashProgram = 0x95,
+    DAP_Vendor_ImageErase = 0x96,
+    DAP_Vendor_ImageWrite = 0x97,
+    DAP_Vendor_ImageCommit = 0x98,
+    DAP_Vendor_ImageVerify = 0x99,
+
     // Unimplemented Command Response
     Unimplemented = 0xFF,
 }
@@ -134,6 +177,58 @@ enum SWOControl {
     Start = 1,
 }
 
+/// Standard SWJ-DP protocol selection sequences, see ADIv5.2 B5.3
+#[derive(TryFromPrimitive)]
+#[repr(u8)]
+enum SWJSequence {
+    LineReset = 0,
+    JTAGToSWD = 1,
+    SWDToJTAG = 2,
+    JTAGToDormant = 3,
+    SWDToDormant = 4,
+    DormantToSWD = 5,
+}
+
+impl SWJSequence {
+    /// Returns the sequence bits, transmitted LSB first, and how many to send.
+    fn bits(&self) -> (&'static [u8], usize) {
+        match self {
+            // At least 50 cycles high, then two idle cycles
+            SWJSequence::LineReset => (&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00], 64),
+            // Line reset, 0xE79E, line reset, idle
+            SWJSequence::JTAGToSWD => (
+                &[
+                    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x9E, 0xE7, 0xFF, 0xFF, 0xFF, 0xFF,
+                    0xFF, 0xFF, 0xFF, 0x00,
+                ],
+                136,
+            ),
+            // Line reset, 0xE73C, then at least 5 cycles high to reset the TAP
+            SWJSequence::SWDToJTAG => (
+                &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x3C, 0xE7, 0xFF],
+                80,
+            ),
+            // At least 5 cycles high to reset the TAP, then the 31-bit 0x33BBBBBA
+            SWJSequence::JTAGToDormant => (&[0xFF, 0xBA, 0xBB, 0xBB, 0x33], 39),
+            // Line reset, then 0xE3BC
+            SWJSequence::SWDToDormant => (
+                &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xE3],
+                72,
+            ),
+            // At least 8 cycles high, the 128-bit selection alert, four cycles low,
+            // the SWD activation code 0x1A, then a line reset and idle
+            SWJSequence::DormantToSWD => (
+                &[
+                    0xFF, 0x92, 0xF3, 0x09, 0x62, 0x95, 0x2D, 0x85, 0x86, 0xE9, 0xAF, 0xDD, 0xE3,
+                    0xA2, 0x0E, 0xBC, 0x19, 0xA0, 0xF1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                    0xFF, 0x00,
+                ],
+                224,
+            ),
+        }
+    }
+}
+
 struct Request<'a> {
     command: Command,
     data: &'a [u8],
@@ -167,6 +262,12 @@ impl<'a> Request<'a> {
         value
     }
 
+    pub fn next_slice(&mut self, len: usize) -> &'a [u8] {
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        value
+    }
+
     pub fn rest(self) -> &'a [u8] {
         &self.data
     }
@@ -222,6 +323,11 @@ impl<'a> ResponseWriter<'a> {
         self.buf[idx..idx + 2].copy_from_slice(&value);
     }
 
+    pub fn write_u32_at(&mut self, idx: usize, value: u32) {
+        let value = value.to_le_bytes();
+        self.buf[idx..idx + 4].copy_from_slice(&value);
+    }
+
     pub fn mut_at(&mut self, idx: usize) -> &mut u8 {
         &mut self.buf[idx]
     }
@@ -239,7 +345,149 @@ impl<'a> ResponseWriter<'a> {
     }
 }
 
+/// Target memory bytes in each bulk memory read packet, after the command and status
+/// bytes, keeping every packet aligned to whole words.
+const MEMORY_PACKET_DATA: usize = DAP2_PACKET_SIZE as usize - 4;
+
+/// Time without data packets after which a bulk write is cancelled
+const DATA_TIMEOUT_US: u32 = 1_000_000;
+
+/// A bulk memory transfer continuing over further packets
+#[derive(Copy, Clone)]
+struct MemoryTransfer {
+    apsel: u8,
+    size: memap::Size,
+    addr: u32,
+    remaining: usize,
+    transferred: usize,
+    failed: bool,
+}
+
+/// Flash pages being programmed from data packets following the command
+struct FlashProgram {
+    programmer: Programmer,
+    remaining: usize,
+    /// Error which ended programming, after which data is discarded
+    error: Option<flashalgo::Error>,
+}
+
+/// Data of a packet continuing the bulk write started by `command`, which
+/// repeats its command byte, or None for any other command.
+fn transfer_data(report: &[u8], command: Command) -> Option<&[u8]> {
+    match report.split_first() {
+        Some((&byte, data)) if byte == command as u8 => Some(data),
+        _ => None,
+    }
+}
+
+/// SWD clock used when programming the stored image without a host
+const STANDALONE_SWD_CLOCK: u32 = 4_000_000;
+
+/// Timeout for the flash algorithm's Init and UnInit functions
+const STANDALONE_INIT_TIMEOUT_MS: u32 = 1000;
+
+/// Values of the flash algorithm's Init function argument selecting what follows
+const INIT_ERASE: u32 = 1;
+const INIT_PROGRAM: u32 = 2;
+
+/// A target word polled in the background, reporting changes to the host
+#[derive(Copy, Clone)]
+struct Watch {
+    apsel: u8,
+    addr: u32,
+    mask: u32,
+    interval_ms: u16,
+    /// Last value reported, or None if the last read failed or nothing was reported yet
+    value: Option<u32>,
+    failed: bool,
+}
+
+/// Most target words read by each memory sample
+const MAX_SAMPLE_ADDRESSES: usize = 16;
+
+/// Target words read together at a regular interval, streamed to the host
+struct Sampler {
+    apsel: u8,
+    period_us: u32,
+    addresses: [u32; MAX_SAMPLE_ADDRESSES],
+    count: usize,
+}
+
+/// Core debug registers used for PC sampling
+const DEMCR: u32 = 0xE000_EDFC;
+const DEMCR_TRCENA: u32 = 1 << 24;
+const DWT_PCSR: u32 = 0xE000_101C;
+
+/// DWT_PCSR sampled at a regular rate, streamed to the host
+struct Profiler {
+    apsel: u8,
+    rate_hz: u32,
+}
+
+/// Records collected in the background until the trace endpoint can take them
+struct StreamBuffer {
+    command: Command,
+    buf: [u8; DAP2_PACKET_SIZE as usize],
+    len: usize,
+    /// Records which did not fit since the last packet was taken
+    dropped: u16,
+}
+
+impl StreamBuffer {
+    /// Bytes before the records of each packet: command and dropped count
+    const HEADER: usize = 3;
+
+    fn new(command: Command) -> Self {
+        StreamBuffer {
+            command,
+            buf: [0; DAP2_PACKET_SIZE as usize],
+            len: Self::HEADER,
+            dropped: 0,
+        }
+    }
+
+    /// Space for a record of `len` bytes, or None if it is dropped.
+    fn record(&mut self, len: usize) -> Option<&mut [u8]> {
+        if self.len + len > self.buf.len() {
+            self.dropped = self.dropped.saturating_add(1);
+            return None;
+        }
+        let record = &mut self.buf[self.len..self.len + len];
+        self.len += len;
+        Some(record)
+    }
+
+    /// Count `n` records which could not be collected.
+    fn drop_records(&mut self, n: u32) {
+        let n = core::cmp::min(n, u16::MAX as u32) as u16;
+        self.dropped = self.dropped.saturating_add(n);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == Self::HEADER && self.dropped == 0
+    }
+
+    /// Copy out a packet of the collected records and start a new one.
+    fn take(&mut self, rbuf: &mut [u8]) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        self.buf[0] = self.command as u8;
+        self.buf[1..3].copy_from_slice(&self.dropped.to_le_bytes());
+        let len = self.len;
+        rbuf[..len].copy_from_slice(&self.buf[..len]);
+        self.len = Self::HEADER;
+        self.dropped = 0;
+        len
+    }
+}
+
+/// APB prescalers are only changed for an SPI clock more than 1/16 faster
+const CLOCK_TOLERANCE: u32 = 16;
+
+#[derive(Copy, Clone)]
 enum DAPMode {
+    /// SWD, with any multidrop target selected through TARGETSEL kept by `swd::SWD`
     SWD,
     JTAG,
 }
@@ -247,28 +495,77 @@ enum DAPMode {
 pub struct DAP<'a> {
     swd: swd::SWD<'a>,
     jtag: jtag::JTAG<'a>,
-    uart: &'a mut UART<'a>,
+    uart: &'a UART<'a>,
     pins: &'a Pins<'a>,
+    flash: &'a Flash,
+    crc: &'a CRC,
+    delay: &'a Delay,
+    timer: &'a Timer,
+    config: Config,
     mode: Option<DAPMode>,
+    swj_clock: Option<u32>,
+    core_frequency: CoreFrequency,
+    clock_config: ClockConfig,
+    clock_request: Option<ClockConfig>,
     swo_streaming: bool,
+    swo_suspended: bool,
     match_retries: usize,
+    memory_read: Option<MemoryTransfer>,
+    memory_write: Option<MemoryTransfer>,
+    watch: Option<Watch>,
+    rtt: Option<RTT>,
+    sampler: Option<Sampler>,
+    samples: StreamBuffer,
+    profiler: Option<Profiler>,
+    pc_samples: StreamBuffer,
+    flash_algo: Option<FlashAlgo>,
+    flash_program: Option<FlashProgram>,
+    /// Timer value when the last bulk write packet arrived
+    data_last_us: u32,
 }
 
 impl<'a> DAP<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         swd: swd::SWD<'a>,
         jtag: jtag::JTAG<'a>,
-        uart: &'a mut UART<'a>,
+        uart: &'a UART<'a>,
         pins: &'a Pins,
+        flash: &'a Flash,
+        crc: &'a CRC,
+        delay: &'a Delay,
+        timer: &'a Timer,
+        config: Config,
     ) -> Self {
         DAP {
             swd,
             jtag,
             uart,
             pins,
+            flash,
+            crc,
+            delay,
+            timer,
+            config,
             mode: None,
+            swj_clock: None,
+            core_frequency: CoreFrequency::F72MHz,
+            clock_config: CoreFrequency::F72MHz.into(),
+            clock_request: None,
             swo_streaming: false,
+            swo_suspended: false,
             match_retries: 5,
+            memory_read: None,
+            memory_write: None,
+            watch: None,
+            rtt: None,
+            sampler: None,
+            samples: StreamBuffer::new(Command::DAP_Vendor_Sample),
+            profiler: None,
+            pc_samples: StreamBuffer::new(Command::DAP_Vendor_PCSample),
+            flash_algo: None,
+            flash_program: None,
+            data_last_us: 0,
         }
     }
 
@@ -281,6 +578,23 @@ impl<'a> DAP<'a> {
         rbuf: &mut [u8],
         version: DAPVersion,
     ) -> usize {
+        // Packets following a bulk write command carry its data after the
+        // same command byte, while any other command cancels the write
+        if let DAPVersion::V2 = version {
+            if self.memory_write.is_some() {
+                match transfer_data(report, Command::DAP_Vendor_MemoryWrite) {
+                    Some(data) => return self.process_memory_write_data(data, rbuf),
+                    None => self.memory_write = None,
+                }
+            }
+            if self.flash_program.is_some() {
+                match transfer_data(report, Command::DAP_Vendor_FlashProgram) {
+                    Some(data) => return self.process_flash_program_data(data, rbuf),
+                    None => self.cancel_flash_program(),
+                }
+            }
+        }
+
         let req = match Request::from_report(report) {
             Some(req) => req,
             None => return 0,
@@ -316,23 +630,511 @@ impl<'a> DAP<'a> {
                 // Do not send a response for transfer abort commands
                 return 0;
             }
+            Command::DAP_Vendor_ConfigRead => self.process_vendor_config_read(req, resp),
+            Command::DAP_Vendor_ConfigWrite => self.process_vendor_config_write(req, resp),
+            Command::DAP_Vendor_ClockInfo => self.process_vendor_clock_info(req, resp),
+            Command::DAP_Vendor_CoreFrequency => self.process_vendor_core_frequency(req, resp),
+            Command::DAP_Vendor_SWDSampling => self.process_vendor_swd_sampling(req, resp),
+            Command::DAP_Vendor_SWDCalibrate => self.process_vendor_swd_calibrate(req, resp),
+            Command::DAP_Vendor_SWDClockDiscovery => {
+                self.process_vendor_swd_clock_discovery(req, resp)
+            }
+            Command::DAP_Vendor_SWDSelectTarget => {
+                self.process_vendor_swd_select_target(req, resp)
+            }
+            Command::DAP_Vendor_SWJSequence => self.process_vendor_swj_sequence(req, resp),
+            Command::DAP_Vendor_SWDRecovery => self.process_vendor_swd_recovery(req, resp),
+            Command::DAP_Vendor_SWDCacheStats => self.process_vendor_swd_cache_stats(req, resp),
+            Command::DAP_Vendor_MemoryRead => self.process_vendor_memory_read(req, resp, version),
+            Command::DAP_Vendor_MemoryWrite => {
+                self.process_vendor_memory_write(req, resp, version)
+            }
+            Command::DAP_Vendor_MemoryCRC => self.process_vendor_memory_crc(req, resp),
+            Command::DAP_Vendor_MemoryErased => self.process_vendor_memory_erased(req, resp),
+            Command::DAP_Vendor_Watch => self.process_vendor_watch(req, resp),
+            Command::DAP_Vendor_RTT => self.process_vendor_rtt(req, resp),
+            Command::DAP_Vendor_Sample => self.process_vendor_sample(req, resp),
+            Command::DAP_Vendor_PCSample => self.process_vendor_pc_sample(req, resp),
+            Command::DAP_Vendor_FlashAlgo => self.process_vendor_flash_algo(req, resp),
+            Command::DAP_Vendor_FlashCall => self.process_vendor_flash_call(req, resp),
+            Command::DAP_Vendor_FlashProgram => {
+                self.process_vendor_flash_program(req, resp, version)
+            }
+            Command::DAP_Vendor_ImageErase => self.process_vendor_image_erase(req, resp),
+            Command::DAP_Vendor_ImageWrite => self.process_vendor_image_write(req, resp),
+            Command::DAP_Vendor_ImageCommit => self.process_vendor_image_commit(req, resp),
+            Command::DAP_Vendor_ImageVerify => self.process_vendor_image_verify(req, resp),
             Command::Unimplemented => {}
         }
 
         resp.idx
     }
 
+    /// Whether the last command has further reply packets to send.
+    pub fn has_continuation(&self) -> bool {
+        self.memory_read.is_some()
+    }
+
+    /// Write the next reply packet of the last command into `rbuf`.
+    ///
+    /// Returns number of bytes written, or 0 if there is nothing to send.
+    pub fn process_continuation(&mut self, rbuf: &mut [u8]) -> usize {
+        if self.memory_read.is_none() {
+            return 0;
+        }
+        let resp = &mut ResponseWriter::new(Command::DAP_Vendor_MemoryRead, rbuf);
+        self.continue_memory_read(resp);
+        resp.idx
+    }
+
+    /// Drop any multi-packet transfer in progress.
+    ///
+    /// Must be called while the target is still connected, to let a page
+    /// already being programmed finish.
+    pub fn abort_continuation(&mut self) {
+        self.memory_read = None;
+        self.memory_write = None;
+        self.cancel_flash_program();
+    }
+
+    /// Cancel a bulk write whose host has stopped sending data.
+    pub fn check_data_timeout(&mut self) {
+        if self.memory_write.is_none() && self.flash_program.is_none() {
+            return;
+        }
+        if self.timer.now_us().wrapping_sub(self.data_last_us) > DATA_TIMEOUT_US {
+            self.memory_write = None;
+            self.cancel_flash_program();
+        }
+    }
+
+    /// Restart the bulk write data timeout.
+    fn data_received(&mut self) {
+        self.data_last_us = self.timer.now_us();
+    }
+
+    /// Interval in milliseconds at which `poll_watch` should be called, if watching.
+    pub fn watch_interval(&self) -> Option<u32> {
+        self.watch.map(|watch| watch.interval_ms as u32)
+    }
+
+    /// Read the watched word, writing an event packet into `rbuf` if its
+    /// masked value changed or reading it started or stopped failing.
+    ///
+    /// Returns number of bytes written, or 0 if there is nothing to report.
+    pub fn poll_watch(&mut self, rbuf: &mut [u8]) -> usize {
+        let mut watch = match self.watch {
+            Some(watch) => watch,
+            None => return 0,
+        };
+
+        let select = match self.host_select() {
+            Some(select) => select,
+            None => return 0,
+        };
+
+        let result = MemAP::new(&mut self.swd, watch.apsel).peek(watch.addr, select);
+        let resp = &mut ResponseWriter::new(Command::DAP_Vendor_Watch, rbuf);
+        let len = match result {
+            Ok(value) => {
+                let changed = match watch.value {
+                    Some(last) => (last ^ value) & watch.mask != 0,
+                    None => true,
+                };
+                if !changed && !watch.failed {
+                    return 0;
+                }
+                watch.value = Some(value);
+                watch.failed = false;
+                resp.write_ok();
+                resp.write_u32(value);
+                resp.idx
+            }
+            Err(_) if watch.failed => 0,
+            Err(_) => {
+                watch.value = None;
+                watch.failed = true;
+                resp.write_err();
+                resp.write_u32(0);
+                resp.idx
+            }
+        };
+        self.watch = Some(watch);
+        if let Err(e) = result {
+            self.background_failed(e);
+        }
+        len
+    }
+
+    /// Whether any background task needs `App::poll` to keep running while idle.
+    pub fn has_background_tasks(&self) -> bool {
+        self.watch.is_some()
+            || self.rtt.is_some()
+            || self.sampler.is_some()
+            || self.profiler.is_some()
+    }
+
+    /// SELECT as last written by the host, if background tasks may access the
+    /// target now: in SWD mode between host transfers, once SELECT is known
+    /// so it can be restored afterwards.
+    ///
+    /// Background tasks are also suspended while a flash algorithm is loaded,
+    /// as it runs on the target between commands.
+    fn host_select(&self) -> Option<u32> {
+        if self.memory_write.is_some()
+            || self.flash_algo.is_some()
+            || self.flash_program.is_some()
+        {
+            return None;
+        }
+        match (self.mode, self.swd.cached(swd::CachedRegister::SELECT)) {
+            (Some(DAPMode::SWD), Some(select)) => Some(select),
+            _ => None,
+        }
+    }
+
+    /// Handle a failed background access. If the host's AP state could not be
+    /// restored, its next transfers would go astray, so all background tasks stop.
+    fn background_failed(&mut self, error: memap::PreserveError) {
+        if let memap::PreserveError::Restore(_) = error {
+            self.stop_background_tasks();
+        }
+    }
+
+    fn stop_background_tasks(&mut self) {
+        self.watch = None;
+        self.rtt = None;
+        self.sampler = None;
+        self.profiler = None;
+    }
+
+    /// Whether an RTT control block is being bridged to the serial port.
+    pub fn rtt_active(&self) -> bool {
+        self.rtt.is_some()
+    }
+
+    /// Pass new RTT up channel data to `write` and data from `read` to the
+    /// down channel, using `buf` to hold it.
+    ///
+    /// `write` and `read` return the number of bytes they accepted or provided.
+    pub fn poll_rtt<W, R>(&mut self, buf: &mut [u8], write: W, read: R)
+    where
+        W: FnOnce(&[u8]) -> usize,
+        R: FnOnce(&mut [u8]) -> usize,
+    {
+        let rtt = match &self.rtt {
+            Some(rtt) => rtt,
+            None => return,
+        };
+
+        let select = match self.host_select() {
+            Some(select) => select,
+            None => return,
+        };
+
+        let result = MemAP::new(&mut self.swd, rtt.apsel()).preserving(select, |mem| {
+            rtt.poll_up(mem, buf, write)?;
+            rtt.poll_down(mem, buf, read)
+        });
+        if let Err(e) = result {
+            self.background_failed(e);
+        }
+    }
+
+    /// Interval in microseconds at which `sample` should be called, if sampling.
+    pub fn sample_period(&self) -> Option<u32> {
+        self.sampler.as_ref().map(|sampler| sampler.period_us)
+    }
+
+    /// Read each sampled word, recording them with `timestamp_us` to stream to the host.
+    ///
+    /// `missed` is the number of sample periods which passed without a sample,
+    /// counted as dropped.
+    pub fn sample(&mut self, timestamp_us: u32, missed: u32) {
+        let sampler = match &self.sampler {
+            Some(sampler) => sampler,
+            None => return,
+        };
+        self.samples.drop_records(missed);
+
+        let select = match self.host_select() {
+            Some(select) => select,
+            None => {
+                self.samples.drop_records(1);
+                return;
+            }
+        };
+
+        let addresses = &sampler.addresses[..sampler.count];
+        let record = match self.samples.record(4 + 4 * addresses.len()) {
+            Some(record) => record,
+            None => return,
+        };
+        record[..4].copy_from_slice(&timestamp_us.to_le_bytes());
+        let values = &mut record[4..];
+        let result = MemAP::new(&mut self.swd, sampler.apsel).preserving(select, |mem| {
+            for (&addr, value) in addresses.iter().zip(values.chunks_exact_mut(4)) {
+                value.copy_from_slice(&mem.read_word(addr)?.to_le_bytes());
+            }
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            // Failed samples are left as zero
+            values.iter_mut().for_each(|b| *b = 0);
+            self.background_failed(e);
+        }
+    }
+
+    /// Write a packet of collected samples into `rbuf`.
+    ///
+    /// Returns number of bytes written, or 0 if there is nothing to send.
+    pub fn take_samples(&mut self, rbuf: &mut [u8]) -> usize {
+        self.samples.take(rbuf)
+    }
+
+    /// Rate in Hz at which `sample_pc` should be called, if profiling.
+    pub fn pc_sample_rate(&self) -> Option<u32> {
+        self.profiler.as_ref().map(|profiler| profiler.rate_hz)
+    }
+
+    /// Read DWT_PCSR, recording the PC to stream to the host.
+    ///
+    /// `missed` is the number of sample periods which passed without a sample,
+    /// counted as dropped.
+    pub fn sample_pc(&mut self, missed: u32) {
+        let apsel = match &self.profiler {
+            Some(profiler) => profiler.apsel,
+            None => return,
+        };
+        self.pc_samples.drop_records(missed);
+
+        let select = match self.host_select() {
+            Some(select) => select,
+            None => {
+                self.pc_samples.drop_records(1);
+                return;
+            }
+        };
+
+        match MemAP::new(&mut self.swd, apsel).peek(DWT_PCSR, select) {
+            Ok(pc) => {
+                if let Some(record) = self.pc_samples.record(4) {
+                    record.copy_from_slice(&pc.to_le_bytes());
+                }
+            }
+            Err(e) => {
+                self.pc_samples.drop_records(1);
+                self.background_failed(e);
+            }
+        }
+    }
+
+    /// Write a packet of collected PC samples into `rbuf`.
+    ///
+    /// Returns number of bytes written, or 0 if there is nothing to send.
+    pub fn take_pc_samples(&mut self, rbuf: &mut [u8]) -> usize {
+        self.pc_samples.take(rbuf)
+    }
+
+    /// Release the target and stop SWO capture while the host is suspended.
+    ///
+    /// The connection mode and SWJ clock are kept so `resume` can restore them.
+    pub fn suspend(&mut self) {
+        // The host does not continue transfers across a suspend
+        self.abort_continuation();
+        self.pins.high_impedance_mode();
+        self.swd.spi_disable();
+        self.jtag.spi_disable();
+        self.swo_suspended = self.uart.is_active();
+        if self.swo_suspended {
+            self.uart.stop();
+        }
+    }
+
+    /// Restore the state saved by `suspend`.
+    ///
+    /// Must be called after `update_clocks` for the clock configuration in use.
+    pub fn resume(&mut self) {
+        match self.mode {
+            Some(DAPMode::SWD) => {
+                self.pins.swd_mode();
+                self.swd.spi_enable();
+                // The target may have lost power while we were suspended
+                self.swd.invalidate_cache();
+            }
+            Some(DAPMode::JTAG) => {
+                self.pins.jtag_mode();
+                self.jtag.spi_enable();
+            }
+            None => (),
+        }
+
+        // Multidrop targets need selecting again after the bus was released
+        if let (Some(DAPMode::SWD), Some(target)) = (self.mode, self.swd.target()) {
+            self.swd.reset_and_select(Some(target)).ok();
+        }
+        if self.swo_suspended {
+            self.uart.start();
+            self.swo_suspended = false;
+        }
+    }
+
+    /// Set the core frequency, which must already be applied.
+    ///
+    /// SWJ clock requests only change the APB prescalers, keeping this core clock.
+    pub fn set_core_frequency(&mut self, frequency: CoreFrequency) {
+        self.core_frequency = frequency;
+        self.clock_config = frequency.into();
+    }
+
+    /// Clock configuration for the selected core frequency and the current SWJ clock.
+    ///
+    /// The caller applies it on a reset or resume, after a suspend slowed the
+    /// core, then calls `update_clocks`.
+    pub fn core_clocks(&self) -> ClockConfig {
+        let config = ClockConfig::from(self.core_frequency);
+        match self.swj_clock {
+            Some(clock) => self.select_clock_config(config, clock),
+            None => config,
+        }
+    }
+
+    /// Take the clock configuration requested by the last command, if it changed.
+    ///
+    /// SWJ clock requests only change APB prescalers, which is safe while USB is
+    /// in use, while core frequency requests relock the PLL. The caller must
+    /// apply it to the clock tree then call `update_clocks`.
+    pub fn take_clock_request(&mut self) -> Option<ClockConfig> {
+        self.clock_request.take()
+    }
+
+    /// Record the clock configuration now applied, and reapply the SWJ clock
+    /// for the new SPI base clocks.
+    pub fn update_clocks(&mut self, config: ClockConfig) {
+        self.clock_config = config;
+        self.clock_request = None;
+        if let Some(clock) = self.swj_clock {
+            self.jtag.set_clock(clock);
+            self.swd.set_clock(clock);
+        }
+    }
+
+    /// Disconnect from the target and stop SWO capture, as after a USB reset.
+    pub fn disconnect(&mut self) {
+        self.release_target();
+        self.uart.stop();
+        self.swo_streaming = false;
+        self.swo_suspended = false;
+    }
+
+    /// Stop everything accessing the target, then release its pins.
+    fn release_target(&mut self) {
+        self.abort_continuation();
+        self.stop_background_tasks();
+        self.flash_algo = None;
+        self.pins.high_impedance_mode();
+        self.mode = None;
+        self.swd.spi_disable();
+        self.swd.reset_state();
+        self.jtag.spi_disable();
+    }
+
+    /// Program the target from the image stored in probe flash, if it is set
+    /// to be programmed on boot, then disconnect.
+    ///
+    /// Returns None if there is no such image, otherwise whether the image
+    /// was programmed and verified.
+    pub fn program_stored_image(&mut self) -> Option<bool> {
+        let image = StoredImage::load(self.crc).filter(|image| image.program_on_boot())?;
+        if !image.verify(self.crc) {
+            return Some(false);
+        }
+
+        self.pins.swd_mode();
+        self.swd.spi_enable();
+        self.swd.reset_state();
+        self.swd.set_clock(STANDALONE_SWD_CLOCK);
+        self.mode = Some(DAPMode::SWD);
+        let (seq, nbits) = SWJSequence::JTAGToSWD.bits();
+        self.swj_sequence(seq, nbits);
+
+        let passed = matches!(self.program_image(&image), Ok(true));
+        self.disconnect();
+        Some(passed)
+    }
+
+    /// Load the flash algorithm, erase, program and verify `image`, then
+    /// reset the target.
+    ///
+    /// Returns whether the target's CRC of the programmed image matched.
+    fn program_image(&mut self, image: &StoredImage) -> flashalgo::Result<bool> {
+        use flashalgo::Function;
+
+        self.swd.reset_and_select(None)?;
+        self.swd.clear_errors()?;
+        if !self.swd.power_up()? {
+            return Err(flashalgo::Error::NotReady);
+        }
+
+        let delay = self.delay;
+        let crc = self.crc;
+        let descriptor = image.descriptor;
+        let algo = FlashAlgo::new(image.apsel, descriptor);
+        let mut mem = MemAP::new(&mut self.swd, image.apsel);
+        algo.halt(&mut mem)?;
+        mem.write_bytes(descriptor.load_address, image.algorithm())?;
+
+        // Erase every sector the image covers
+        let timeout_ms = STANDALONE_INIT_TIMEOUT_MS;
+        let args = [image.address, 0, INIT_ERASE];
+        algo.call_optional(&mut mem, delay, Function::Init, args, timeout_ms)?;
+        let end = image.address + image.image_len;
+        let mut addr = image.address - image.address % image.sector_size;
+        while addr < end {
+            let args = [addr, 0, 0];
+            algo.call(&mut mem, delay, Function::EraseSector, args, image.erase_timeout_ms)?;
+            addr += image.sector_size;
+        }
+        let args = [INIT_ERASE, 0, 0];
+        algo.call_optional(&mut mem, delay, Function::UnInit, args, timeout_ms)?;
+
+        // Program whole pages, padding the last with the erased value
+        let args = [image.address, 0, INIT_PROGRAM];
+        algo.call_optional(&mut mem, delay, Function::Init, args, timeout_ms)?;
+        let mut programmer = Programmer::new(image.address);
+        programmer.write(&algo, &mut mem, delay, image.image())?;
+        let page_size = descriptor.page_size;
+        let mut padding = (page_size - image.image_len % page_size) % page_size;
+        let blank = [0xFF; 64];
+        while padding > 0 {
+            let n = core::cmp::min(padding as usize, blank.len());
+            programmer.write(&algo, &mut mem, delay, &blank[..n])?;
+            padding -= n as u32;
+        }
+        programmer.finish(&algo, &mut mem, delay)?;
+        let args = [INIT_PROGRAM, 0, 0];
+        algo.call_optional(&mut mem, delay, Function::UnInit, args, timeout_ms)?;
+
+        // Verify the image by its CRC, computed as it is read back
+        crc.reset();
+        let mut buf = [0; DAP2_PACKET_SIZE as usize];
+        mem.read_with(image.address, image.image_len as usize, &mut buf, |data| {
+            crate::bsp::bootload::feed_watchdog();
+            crc.update(data);
+            true
+        })?;
+        let verified = crc.finish() == image.image_crc;
+
+        algo.reset(&mut mem)?;
+        Ok(verified)
+    }
+
     /// Returns true if SWO streaming is currently active.
     pub fn is_swo_streaming(&self) -> bool {
         self.uart.is_active() && self.swo_streaming
     }
 
-    /// Polls the UART buffer for new SWO data, returning
-    /// number of bytes written to buffer.
-    pub fn read_swo(&mut self, buf: &mut [u8]) -> usize {
-        self.uart.read(buf)
-    }
-
     fn process_info(&mut self, mut req: Request, resp: &mut ResponseWriter, version: DAPVersion) {
         match DAPInfoID::try_from(req.next_u8()) {
             // Return 0-length string for VendorID, ProductID, SerialNumber
@@ -412,6 +1214,7 @@ impl<'a> DAP<'a> {
             Ok(ConnectPort::Default) | Ok(ConnectPort::SWD) => {
                 self.pins.swd_mode();
                 self.swd.spi_enable();
+                self.swd.reset_state();
                 self.mode = Some(DAPMode::SWD);
                 resp.write_u8(ConnectPortResponse::SWD as u8);
             }
@@ -423,15 +1226,20 @@ impl<'a> DAP<'a> {
             }
             _ => {
                 resp.write_u8(ConnectPortResponse::Failed as u8);
+                return;
             }
         }
+
+        // A clock set before connecting was matched for SWD, so match it
+        // again for the SPI of the selected mode
+        if let Some(clock) = self.swj_clock {
+            self.set_swj_clock(clock);
+        }
     }
 
     fn process_disconnect(&mut self, _req: Request, resp: &mut ResponseWriter) {
-        self.pins.high_impedance_mode();
-        self.mode = None;
-        self.swd.spi_disable();
-        self.jtag.spi_disable();
+        // SWO capture is independent of the connection, so keeps running
+        self.release_target();
         resp.write_ok();
     }
 
@@ -450,7 +1258,7 @@ impl<'a> DAP<'a> {
 
     fn process_delay(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let delay = req.next_u16() as u32;
-        cortex_m::asm::delay(48 * delay);
+        self.delay.delay_long_us(delay);
         resp.write_ok();
     }
 
@@ -499,8 +1307,8 @@ impl<'a> DAP<'a> {
             }
         }
 
-        // Delay required time in µs
-        cortex_m::asm::delay(42 * wait);
+        // Delay required time in µs, which CMSIS-DAP limits to 3 s
+        self.delay.delay_long_us(core::cmp::min(wait, 3_000_000));
 
         // Read and return pin state
         let state = ((self.pins.spi1_clk.get_state() as u8) << SWCLK_POS)
@@ -514,16 +1322,70 @@ impl<'a> DAP<'a> {
 
     fn process_swj_clock(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let clock = req.next_u32();
-
-        self.jtag.set_clock(clock);
-        let valid = self.swd.set_clock(clock);
-        if valid {
+        if self.set_swj_clock(clock) {
             resp.write_ok();
         } else {
             resp.write_err();
         }
     }
 
+    /// Set the SWJ clock as for DAP_SWJ_Clock, returning false if invalid.
+    fn set_swj_clock(&mut self, clock: u32) -> bool {
+        if clock == 0 {
+            return false;
+        }
+        self.swj_clock = Some(clock);
+
+        // Prefer APB prescalers giving a finer match to the requested frequency
+        self.request_clocks(self.select_clock_config(self.clock_config, clock));
+
+        self.jtag.set_clock(clock);
+        self.swd.set_clock(clock)
+    }
+
+    /// Choose APB prescalers for `current`'s PLL output giving the fastest SPI
+    /// clock at most `max_frequency` for the current mode.
+    ///
+    /// The system clock is never changed, so the switch is safe while USB is in
+    /// use. `current` is kept unless another configuration is more than
+    /// 1/`CLOCK_TOLERANCE` faster, or if the SPI can't go slow enough and we
+    /// bit-bang.
+    fn select_clock_config(&self, current: ClockConfig, max_frequency: u32) -> ClockConfig {
+        let jtag = matches!(self.mode, Some(DAPMode::JTAG));
+        let spi_clock = |config: &ClockConfig| {
+            let base = if jtag { config.pclk1() } else { config.pclk2() };
+            SPIPrescaler::for_frequency(base, max_frequency)
+                .map(|prescaler| base / prescaler.divisor())
+        };
+
+        let current_clock = spi_clock(&current);
+        let mut best = current;
+        let mut best_clock = current_clock.unwrap_or(0);
+        for config in current.prescaler_variants() {
+            match spi_clock(&config) {
+                Some(clock) if clock > best_clock => {
+                    best = config;
+                    best_clock = clock;
+                }
+                _ => (),
+            }
+        }
+
+        match current_clock {
+            Some(clock) if clock >= best_clock - best_clock / CLOCK_TOLERANCE => current,
+            _ => best,
+        }
+    }
+
+    /// Request a change of clock configuration, applied after this command.
+    fn request_clocks(&mut self, config: ClockConfig) {
+        self.clock_request = if config != self.clock_config {
+            Some(config)
+        } else {
+            None
+        };
+    }
+
     fn process_swj_sequence(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let nbits: usize = match req.next_u8() {
             // CMSIS-DAP says 0 means 256 bits
@@ -541,22 +1403,29 @@ impl<'a> DAP<'a> {
             return;
         };
 
-        match self.mode {
-            Some(DAPMode::SWD) => {
-                self.pins.jtag_mode();
+        if self.swj_sequence(seq, nbits) {
+            resp.write_ok();
+        } else {
+            resp.write_err();
+        }
+    }
+
+    /// Clock out `nbits` of `seq` on SWDIO/TMS, returning false if not connected.
+    fn swj_sequence(&mut self, seq: &[u8], nbits: usize) -> bool {
+        match self.mode {
+            Some(DAPMode::SWD) => {
+                self.pins.jtag_mode();
                 self.jtag.tms_sequence(seq, nbits);
                 self.pins.swd_mode();
+                // The sequence may have reset the DP
+                self.swd.invalidate_cache();
             }
             Some(DAPMode::JTAG) => {
                 self.jtag.tms_sequence(seq, nbits);
             }
-            None => {
-                resp.write_err();
-                return;
-            }
+            None => return false,
         }
-
-        resp.write_ok();
+        true
     }
 
     fn process_swd_configure(&mut self, mut req: Request, resp: &mut ResponseWriter) {
@@ -809,6 +1678,17 @@ impl<'a> DAP<'a> {
         resp.write_u16(0);
         resp.write_u8(0);
 
+        // Consecutive writes are streamed by the SWD block write fast path.
+        // A short packet only writes the whole words it holds.
+        if !rnw {
+            let data = req.rest();
+            let data = &data[..4 * core::cmp::min(ntransfers as usize, data.len() / 4)];
+            let (written, result) = self.swd.write_block(apndp.into(), a, data);
+            let failed = result.check(resp.mut_at(3)).is_none() as u16;
+            resp.write_u16_at(1, written as u16 + failed);
+            return;
+        }
+
         // Keep track of how many transfers we executed,
         // so if there is an error the host knows where
         // it happened.
@@ -823,41 +1703,33 @@ impl<'a> DAP<'a> {
 
         for transfer_idx in 0..ntransfers {
             transfers = transfer_idx;
-            if rnw {
-                // Handle repeated reads
-                let read_value = if apndp {
-                    // For AP reads, the first read was posted, so on the final
-                    // read we need to read RDBUFF instead of the AP register.
-                    if transfer_idx < ntransfers - 1 {
-                        match self.swd.read_ap(a).check(resp.mut_at(3)) {
-                            Some(v) => v,
-                            None => break,
-                        }
-                    } else {
-                        let rdbuff = swd::DPRegister::RDBUFF.into();
-                        match self.swd.read_dp(rdbuff).check(resp.mut_at(3)) {
-                            Some(v) => v,
-                            None => break,
-                        }
+
+            // Handle repeated reads
+            let read_value = if apndp {
+                // For AP reads, the first read was posted, so on the final
+                // read we need to read RDBUFF instead of the AP register.
+                if transfer_idx < ntransfers - 1 {
+                    match self.swd.read_ap(a).check(resp.mut_at(3)) {
+                        Some(v) => v,
+                        None => break,
                     }
                 } else {
-                    // For DP reads, no special care required
-                    match self.swd.read_dp(a).check(resp.mut_at(3)) {
+                    let rdbuff = swd::DPRegister::RDBUFF.into();
+                    match self.swd.read_dp(rdbuff).check(resp.mut_at(3)) {
                         Some(v) => v,
                         None => break,
                     }
-                };
-
-                // Save read register value to response
-                resp.write_u32(read_value);
+                }
             } else {
-                // Handle repeated register writes
-                let write_value = req.next_u32();
-                let result = self.swd.write(apndp.into(), a, write_value);
-                if result.check(resp.mut_at(3)).is_none() {
-                    break;
+                // For DP reads, no special care required
+                match self.swd.read_dp(a).check(resp.mut_at(3)) {
+                    Some(v) => v,
+                    None => break,
                 }
-            }
+            };
+
+            // Save read register value to response
+            resp.write_u32(read_value);
         }
 
         // Write number of transfers to response
@@ -869,6 +1741,825 @@ impl<'a> DAP<'a> {
         // processing anything else, since processing blocks checking for
         // new requests. Therefore there's nothing to do here.
     }
+
+    fn process_vendor_config_read(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let key = match ConfigKey::try_from(req.next_u8()) {
+            Ok(key) => key,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        resp.write_ok();
+        // Skip length for now
+        resp.skip(1);
+        let len = self.config.get(key, resp.remaining());
+        resp.skip(len);
+        // Go back and write length
+        resp.write_u8_at(2, len as u8);
+    }
+
+    fn process_vendor_config_write(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let key = req.next_u8();
+        let len = req.next_u8() as usize;
+        let value = req.rest();
+        let key = match ConfigKey::try_from(key) {
+            Ok(key) if len <= value.len() => key,
+            _ => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        // Validate against a copy so a rejected value leaves the configuration intact.
+        // The stored configuration is applied on the next reset.
+        let mut config = self.config.clone();
+        match config.set(key, &value[..len]).and_then(|_| config.save(self.flash)) {
+            Ok(_) => {
+                self.config = config;
+                resp.write_ok();
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_clock_info(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        resp.write_ok();
+        resp.write_u32(self.swd.clock());
+        resp.write_u32(self.jtag.clock());
+        resp.write_u32(self.clock_config.sysclk());
+    }
+
+    fn process_vendor_core_frequency(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        // Zero only reads back the current setting
+        let mhz = req.next_u8();
+        if mhz != 0 {
+            match CoreFrequency::from_mhz(mhz as u32) {
+                Some(frequency) => {
+                    // Applied once the response has been sent
+                    self.core_frequency = frequency;
+                    self.request_clocks(self.core_clocks());
+                }
+                None => {
+                    resp.write_err();
+                    return;
+                }
+            }
+        }
+
+        resp.write_ok();
+        resp.write_u8((self.core_clocks().sysclk() / 1_000_000) as u8);
+    }
+
+    fn process_vendor_swd_sampling(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let point = match swd::SamplePoint::try_from(req.next_u8()) {
+            Ok(point) => point,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+        let parity_delay = req.next_u8() as u32;
+        self.swd.set_sampling(point, parity_delay);
+        resp.write_ok();
+    }
+
+    fn process_vendor_swd_clock_discovery(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        // Passes of DPIDR and AP IDR reads at each clock setting
+        const PASSES: usize = 32;
+
+        let apply = (req.next_u8() & 1) != 0;
+        let apsel = req.next_u8();
+        match self.mode {
+            Some(DAPMode::SWD) => (),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+
+        // Recovery would hide the errors we are counting
+        let recovery = self.swd.recovery();
+        self.swd.set_recovery(false);
+
+        // Reading the AP IDR moves SELECT to its bank, so put back the host's
+        // SELECT afterwards if we know it
+        let select = self.swd.cached(swd::CachedRegister::SELECT);
+
+        // Take reference values at the slowest SPI clock
+        let base = self.swd.base_clock();
+        self.swd.set_clock(base >> 8);
+        let reference = match self.swd.read_ids(apsel) {
+            Ok(ids) => ids,
+            Err(_) => {
+                self.restore_swd_clock();
+                self.finish_clock_discovery(select, recovery);
+                resp.write_err();
+                return;
+            }
+        };
+
+        resp.write_ok();
+        // Skip the result frequency until we know it
+        let result_idx = resp.idx;
+        resp.skip(4);
+        resp.write_u8(8);
+
+        // Try each prescaler from slowest to fastest
+        let mut best = 0;
+        for shift in (1..=8).rev() {
+            let clock = base >> shift;
+            self.swd.set_clock(clock);
+            let mut errors = 0u8;
+            for _ in 0..PASSES {
+                match self.swd.read_ids(apsel) {
+                    Ok(ids) if ids == reference => (),
+                    Ok(_) => errors = errors.saturating_add(1),
+                    Err(_) => {
+                        errors = errors.saturating_add(1);
+                        self.swd.clear_errors().ok();
+                    }
+                }
+            }
+            if errors == 0 {
+                best = clock;
+            }
+            resp.write_u32(clock);
+            resp.write_u8(errors);
+        }
+        resp.write_u32_at(result_idx, best);
+
+        if apply && best != 0 {
+            // Apply the tested clock on the current clock tree, which
+            // `set_swj_clock` might change
+            self.swj_clock = Some(best);
+            self.swd.set_clock(best);
+            self.jtag.set_clock(best);
+        } else {
+            self.restore_swd_clock();
+        }
+        self.finish_clock_discovery(select, recovery);
+    }
+
+    /// Leave the target as clock discovery found it, without sticky errors
+    /// from the fastest settings and with the host's SELECT value.
+    fn finish_clock_discovery(&mut self, select: Option<u32>, recovery: bool) {
+        self.swd.clear_errors().ok();
+        if let Some(select) = select {
+            self.swd.write_dp(swd::DPRegister::SELECT.into(), select).ok();
+        }
+        self.swd.set_recovery(recovery);
+    }
+
+    /// Restore the SWD clock set by the host after changing it.
+    fn restore_swd_clock(&mut self) {
+        match self.swj_clock {
+            Some(clock) => {
+                self.swd.set_clock(clock);
+            }
+            // Back to the default clock from `spi_enable`
+            None => self.swd.spi_enable(),
+        }
+    }
+
+    fn process_vendor_swd_select_target(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let target = req.next_u32();
+        match self.mode {
+            Some(DAPMode::SWD) => (),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+
+        match self.swd.reset_and_select(Some(target)) {
+            Ok(dpidr) => {
+                resp.write_ok();
+                resp.write_u32(dpidr);
+            }
+            Err(_) => {
+                self.swd.reset_state();
+                resp.write_err();
+            }
+        }
+    }
+
+    fn process_vendor_swj_sequence(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let sequence = match SWJSequence::try_from(req.next_u8()) {
+            Ok(sequence) => sequence,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        let (seq, nbits) = sequence.bits();
+        if !self.swj_sequence(seq, nbits) {
+            resp.write_err();
+            return;
+        }
+
+        // Every sequence deselects multidrop targets
+        if let Some(DAPMode::SWD) = self.mode {
+            self.swd.reset_state();
+        }
+        resp.write_ok();
+    }
+
+    fn process_vendor_swd_recovery(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        match req.next_u8() {
+            0 => self.swd.set_recovery(false),
+            1 => self.swd.set_recovery(true),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+        resp.write_ok();
+    }
+
+    fn process_vendor_swd_cache_stats(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let reset = (req.next_u8() & 1) != 0;
+        let stats = self.swd.cache_stats();
+        resp.write_ok();
+        for (writes, elided) in stats.writes.iter().zip(stats.elided.iter()) {
+            resp.write_u32(*writes);
+            resp.write_u32(*elided);
+        }
+        if reset {
+            self.swd.reset_cache_stats();
+        }
+    }
+
+    /// Parse and check the arguments common to bulk memory reads and writes,
+    /// then set up the MEM-AP for them.
+    fn start_memory_transfer(
+        &mut self,
+        mut req: Request,
+        version: DAPVersion,
+    ) -> Option<MemoryTransfer> {
+        let apsel = req.next_u8();
+        let size = memap::Size::try_from(req.next_u8()).ok()?;
+        let addr = req.next_u32();
+        let len = req.next_u32() as usize;
+
+        match (self.mode, version) {
+            (Some(DAPMode::SWD), DAPVersion::V2) => (),
+            _ => return None,
+        }
+        if !size.aligned(addr, len) || u64::from(addr) + len as u64 > 1 << 32 {
+            return None;
+        }
+        MemAP::new(&mut self.swd, apsel).setup(size).ok()?;
+
+        Some(MemoryTransfer {
+            apsel,
+            size,
+            addr,
+            remaining: len,
+            transferred: 0,
+            failed: false,
+        })
+    }
+
+    fn process_vendor_memory_read(
+        &mut self,
+        req: Request,
+        resp: &mut ResponseWriter,
+        version: DAPVersion,
+    ) {
+        match self.start_memory_transfer(req, version) {
+            Some(transfer) => {
+                self.memory_read = Some(transfer);
+                self.continue_memory_read(resp);
+            }
+            None => resp.write_err(),
+        }
+    }
+
+    /// Read the next packet of a bulk memory read into `resp`.
+    fn continue_memory_read(&mut self, resp: &mut ResponseWriter) {
+        let mut transfer = match self.memory_read.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+
+        let len = core::cmp::min(transfer.remaining, MEMORY_PACKET_DATA);
+        let status_idx = resp.idx;
+        resp.write_ok();
+        let data = &mut resp.remaining()[..len];
+        let mut mem = MemAP::new(&mut self.swd, transfer.apsel);
+        match mem.read(transfer.addr, transfer.size, data) {
+            Ok(()) => {
+                resp.skip(len);
+                transfer.addr += len as u32;
+                transfer.remaining -= len;
+                if transfer.remaining > 0 {
+                    self.memory_read = Some(transfer);
+                }
+            }
+            // End the transfer early, without the data of this packet
+            Err(_) => resp.write_u8_at(status_idx, ResponseStatus::DAP_ERROR.into()),
+        }
+    }
+
+    fn process_vendor_memory_write(
+        &mut self,
+        req: Request,
+        resp: &mut ResponseWriter,
+        version: DAPVersion,
+    ) {
+        match self.start_memory_transfer(req, version) {
+            Some(transfer) if transfer.remaining > 0 => {
+                self.memory_write = Some(transfer);
+                self.data_received();
+                resp.write_ok();
+            }
+            _ => resp.write_err(),
+        }
+    }
+
+    /// Write the data of one packet of a bulk memory write, replying once all
+    /// data is received.
+    fn process_memory_write_data(&mut self, data: &[u8], rbuf: &mut [u8]) -> usize {
+        let mut transfer = match self.memory_write.take() {
+            Some(transfer) => transfer,
+            None => return 0,
+        };
+        self.data_received();
+
+        let data = &data[..core::cmp::min(data.len(), transfer.remaining)];
+        transfer.remaining -= data.len();
+
+        // After a failure, keep consuming data until the host has sent it all
+        if !transfer.failed {
+            let size = transfer.size;
+            let mut mem = MemAP::new(&mut self.swd, transfer.apsel);
+            let result = if size.aligned(0, data.len()) {
+                mem.setup(size)
+                    .and_then(|_| mem.write(transfer.addr, size, data))
+                    .is_ok()
+            } else {
+                false
+            };
+            if result {
+                transfer.addr += data.len() as u32;
+                transfer.transferred += data.len();
+            } else {
+                transfer.failed = true;
+            }
+        }
+
+        if transfer.remaining > 0 {
+            self.memory_write = Some(transfer);
+            return 0;
+        }
+
+        let resp = &mut ResponseWriter::new(Command::DAP_Vendor_MemoryWrite, rbuf);
+        if transfer.failed {
+            resp.write_err();
+        } else {
+            resp.write_ok();
+        }
+        resp.write_u32(transfer.transferred as u32);
+        resp.idx
+    }
+
+    fn process_vendor_memory_crc(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let addr = req.next_u32();
+        let len = req.next_u32() as usize;
+        if !matches!(self.mode, Some(DAPMode::SWD)) {
+            resp.write_err();
+            return;
+        }
+
+        let crc = self.crc;
+        crc.reset();
+        let mut buf = [0; DAP2_PACKET_SIZE as usize];
+        let result = MemAP::new(&mut self.swd, apsel).read_with(addr, len, &mut buf, |data| {
+            crate::bsp::bootload::feed_watchdog();
+            crc.update(data);
+            true
+        });
+
+        match result {
+            Ok(()) => {
+                resp.write_ok();
+                resp.write_u32(crc.finish());
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_memory_erased(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let addr = req.next_u32();
+        let len = req.next_u32() as usize;
+        if !matches!(self.mode, Some(DAPMode::SWD)) {
+            resp.write_err();
+            return;
+        }
+
+        // Address of the first byte which is not erased, if any
+        let mut dirty = None;
+        let mut offset = addr;
+        let mut buf = [0; DAP2_PACKET_SIZE as usize];
+        let result = MemAP::new(&mut self.swd, apsel).read_with(addr, len, &mut buf, |data| {
+            crate::bsp::bootload::feed_watchdog();
+            if let Some(idx) = data.iter().position(|&b| b != 0xFF) {
+                dirty = Some(offset + idx as u32);
+                return false;
+            }
+            offset += data.len() as u32;
+            true
+        });
+
+        match result {
+            Ok(()) => {
+                resp.write_ok();
+                resp.write_u8(dirty.is_none() as u8);
+                resp.write_u32(dirty.unwrap_or(0));
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_watch(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let addr = req.next_u32();
+        let mask = req.next_u32();
+        let interval_ms = req.next_u16();
+
+        if interval_ms == 0 {
+            self.watch = None;
+            resp.write_ok();
+        } else if addr & 3 == 0 && matches!(self.mode, Some(DAPMode::SWD)) {
+            self.watch = Some(Watch {
+                apsel,
+                addr,
+                mask,
+                interval_ms,
+                value: None,
+                failed: false,
+            });
+            resp.write_ok();
+        } else {
+            resp.write_err();
+        }
+    }
+
+    fn process_vendor_rtt(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if req.next_u8() == 0 {
+            self.rtt = None;
+            resp.write_ok();
+            return;
+        }
+
+        let apsel = req.next_u8();
+        let up = req.next_u8();
+        let down = match req.next_u8() {
+            0xFF => None,
+            down => Some(down),
+        };
+        let addr = req.next_u32();
+        let len = req.next_u32() as usize;
+        self.rtt = None;
+        if !matches!(self.mode, Some(DAPMode::SWD)) {
+            resp.write_err();
+            return;
+        }
+
+        let mut mem = MemAP::new(&mut self.swd, apsel);
+        let control_block = match len {
+            0 => Ok(Some(addr)),
+            len => RTT::search(&mut mem, addr, len),
+        };
+        let rtt = control_block.and_then(|control_block| match control_block {
+            Some(control_block) => RTT::attach(&mut mem, control_block, up, down),
+            None => Ok(None),
+        });
+
+        match rtt {
+            Ok(Some(rtt)) => {
+                resp.write_ok();
+                resp.write_u32(rtt.control_block());
+                self.rtt = Some(rtt);
+            }
+            _ => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_sample(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let period_us = req.next_u32();
+        let count = req.next_u8() as usize;
+        self.sampler = None;
+        self.samples = StreamBuffer::new(Command::DAP_Vendor_Sample);
+
+        if period_us == 0 {
+            resp.write_ok();
+            return;
+        }
+        if count == 0
+            || count > MAX_SAMPLE_ADDRESSES
+            || !matches!(self.mode, Some(DAPMode::SWD))
+        {
+            resp.write_err();
+            return;
+        }
+
+        let mut addresses = [0; MAX_SAMPLE_ADDRESSES];
+        for addr in addresses[..count].iter_mut() {
+            *addr = req.next_u32();
+            if *addr & 3 != 0 {
+                resp.write_err();
+                return;
+            }
+        }
+
+        self.sampler = Some(Sampler {
+            apsel,
+            period_us,
+            addresses,
+            count,
+        });
+        resp.write_ok();
+    }
+
+    fn process_vendor_pc_sample(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let rate_hz = req.next_u32();
+        self.profiler = None;
+        self.pc_samples = StreamBuffer::new(Command::DAP_Vendor_PCSample);
+
+        if rate_hz == 0 {
+            resp.write_ok();
+            return;
+        }
+
+        // The host's AP state must be known so sampling can restore it
+        let select = match self.host_select() {
+            Some(select) => select,
+            None => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        // DWT_PCSR only samples while the DWT is enabled by TRCENA
+        let enabled = MemAP::new(&mut self.swd, apsel).preserving(select, |mem| {
+            let demcr = mem.read_word(DEMCR)?;
+            if demcr & DEMCR_TRCENA == 0 {
+                mem.write_word(DEMCR, demcr | DEMCR_TRCENA)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = enabled {
+            self.background_failed(e);
+            resp.write_err();
+            return;
+        }
+
+        self.profiler = Some(Profiler { apsel, rate_hz });
+        resp.write_ok();
+    }
+
+    fn process_vendor_flash_algo(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let apsel = req.next_u8();
+        let descriptor = flashalgo::Descriptor::from_bytes(req.rest());
+        self.flash_algo = None;
+
+        let descriptor = match (self.mode, descriptor) {
+            (Some(DAPMode::SWD), Some(descriptor)) => descriptor,
+            _ => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        // Functions are run on the halted core, starting from now
+        let algo = FlashAlgo::new(apsel, descriptor);
+        match algo.halt(&mut MemAP::new(&mut self.swd, apsel)) {
+            Ok(()) => {
+                self.flash_algo = Some(algo);
+                resp.write_ok();
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_flash_call(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let function = flashalgo::Function::try_from(req.next_u8());
+        let timeout_ms = req.next_u32();
+        let args = [req.next_u32(), req.next_u32(), req.next_u32()];
+
+        let (algo, function) = match (&self.flash_algo, function) {
+            (Some(algo), Ok(function)) => (algo, function),
+            _ => {
+                resp.write_err();
+                resp.write_u32(0);
+                return;
+            }
+        };
+
+        let mut mem = MemAP::new(&mut self.swd, algo.apsel());
+        match algo.call(&mut mem, self.delay, function, args, timeout_ms) {
+            Ok(result) => {
+                resp.write_ok();
+                resp.write_u32(result);
+            }
+            Err(flashalgo::Error::Failed(result)) => {
+                resp.write_err();
+                resp.write_u32(result);
+            }
+            Err(_) => {
+                resp.write_err();
+                resp.write_u32(0);
+            }
+        }
+    }
+
+    fn process_vendor_flash_program(
+        &mut self,
+        mut req: Request,
+        resp: &mut ResponseWriter,
+        version: DAPVersion,
+    ) {
+        let addr = req.next_u32();
+        let len = req.next_u32() as usize;
+
+        let page_size = match (&self.flash_algo, version) {
+            (Some(algo), DAPVersion::V2) => algo.descriptor().page_size as usize,
+            _ => {
+                resp.write_err();
+                return;
+            }
+        };
+        if len == 0 || len % page_size != 0 || u64::from(addr) + len as u64 > 1 << 32 {
+            resp.write_err();
+            return;
+        }
+
+        self.flash_program = Some(FlashProgram {
+            programmer: Programmer::new(addr),
+            remaining: len,
+            error: None,
+        });
+        self.data_received();
+        resp.write_ok();
+    }
+
+    /// Write the data of one packet of flash pages into target RAM, programming
+    /// each page once it is complete, and reply once all pages are programmed.
+    fn process_flash_program_data(&mut self, data: &[u8], rbuf: &mut [u8]) -> usize {
+        self.data_received();
+        let (mut program, algo) = match (self.flash_program.take(), &self.flash_algo) {
+            (Some(program), Some(algo)) => (program, algo),
+            _ => return 0,
+        };
+
+        let data = &data[..core::cmp::min(data.len(), program.remaining)];
+        program.remaining -= data.len();
+
+        // After a failure, keep consuming data until the host has sent it all
+        let mut mem = MemAP::new(&mut self.swd, algo.apsel());
+        if program.error.is_none() {
+            let mut result = program.programmer.write(algo, &mut mem, self.delay, data);
+            if result.is_ok() && program.remaining == 0 {
+                result = program.programmer.finish(algo, &mut mem, self.delay);
+            }
+            program.error = result.err();
+        }
+
+        if program.remaining > 0 {
+            self.flash_program = Some(program);
+            return 0;
+        }
+
+        let resp = &mut ResponseWriter::new(Command::DAP_Vendor_FlashProgram, rbuf);
+        match program.error {
+            None => resp.write_ok(),
+            Some(_) => resp.write_err(),
+        }
+        resp.write_u32(program.programmer.programmed());
+        match program.error {
+            Some(flashalgo::Error::Failed(result)) => resp.write_u32(result),
+            _ => resp.write_u32(0),
+        }
+        resp.idx
+    }
+
+    /// Stop programming pages whose data will not arrive, letting any page
+    /// already started finish so the algorithm can be called again.
+    fn cancel_flash_program(&mut self) {
+        if let (Some(mut program), Some(algo)) = (self.flash_program.take(), &self.flash_algo) {
+            if program.error.is_none() {
+                let mut mem = MemAP::new(&mut self.swd, algo.apsel());
+                program.programmer.finish(algo, &mut mem, self.delay).ok();
+            }
+        }
+    }
+
+    fn process_vendor_image_erase(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        match StoredImage::erase(self.flash) {
+            Ok(()) => resp.write_ok(),
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_image_write(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let region = standalone::Region::try_from(req.next_u8());
+        let offset = req.next_u32();
+        let len = req.next_u16() as usize;
+        let data = req.rest();
+        let region = match region {
+            Ok(region) if len <= data.len() => region,
+            _ => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        match StoredImage::write(self.flash, region, offset, &data[..len]) {
+            Ok(()) => resp.write_ok(),
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_image_commit(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        // Too long for a CMSIS-DAP v1 report
+        if req.data.len() < 2 + flashalgo::Descriptor::SIZE + 20 {
+            resp.write_err();
+            return;
+        }
+        let apsel = req.next_u8();
+        let flags = req.next_u8();
+        let descriptor = req.next_slice(flashalgo::Descriptor::SIZE);
+
+        let mut image = match flashalgo::Descriptor::from_bytes(descriptor) {
+            Some(descriptor) => StoredImage {
+                apsel,
+                flags,
+                descriptor,
+                algorithm_len: req.next_u32(),
+                address: req.next_u32(),
+                image_len: req.next_u32(),
+                sector_size: req.next_u32(),
+                erase_timeout_ms: req.next_u32(),
+                algorithm_crc: 0,
+                image_crc: 0,
+            },
+            None => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        match image.commit(self.flash, self.crc) {
+            Ok(()) => {
+                resp.write_ok();
+                resp.write_u32(image.algorithm_crc);
+                resp.write_u32(image.image_crc);
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_image_verify(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        match StoredImage::load(self.crc) {
+            Some(image) if image.verify(self.crc) => {
+                resp.write_ok();
+                resp.write_u8(image.flags);
+                resp.write_u32(image.address);
+                resp.write_u32(image.image_len);
+                resp.write_u32(image.image_crc);
+            }
+            _ => resp.write_err(),
+        }
+    }
+
+    fn process_vendor_swd_calibrate(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        match self.mode {
+            Some(DAPMode::SWD) => (),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+
+        match self.swd.calibrate_sampling() {
+            Some(dpidr) => {
+                let (point, parity_delay) = self.swd.sampling();
+                resp.write_ok();
+                resp.write_u8(point.into());
+                resp.write_u8(parity_delay as u8);
+                resp.write_u32(dpidr);
+            }
+            None => resp.write_err(),
+        }
+    }
 }
 
 trait CheckResult<T> {
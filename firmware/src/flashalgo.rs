@@ -0,0 +1,390 @@
+use crate::bsp::{bootload, delay::Delay};
+use crate::memap::MemAP;
+use crate::swd;
+use num_enum::TryFromPrimitive;
+
+/// Cortex-M debug registers
+const DHCSR: u32 = 0xE000_EDF0;
+const DCRSR: u32 = 0xE000_EDF4;
+const DCRDR: u32 = 0xE000_EDF8;
+
+/// DHCSR fields
+const DHCSR_DBGKEY: u32 = 0xA05F << 16;
+const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+const DHCSR_C_HALT: u32 = 1 << 1;
+const DHCSR_S_REGRDY: u32 = 1 << 16;
+const DHCSR_S_HALT: u32 = 1 << 17;
+
+/// AIRCR, and the value requesting a system reset
+const AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = (0x05FA << 16) | (1 << 2);
+
+/// DCRSR bit selecting a register write rather than a read
+const DCRSR_REGWNR: u32 = 1 << 16;
+
+/// Core register numbers in DCRSR
+const R0: u32 = 0;
+const R9: u32 = 9;
+const SP: u32 = 13;
+const LR: u32 = 14;
+const PC: u32 = 15;
+const XPSR: u32 = 16;
+
+/// xPSR with only the Thumb state bit set
+const XPSR_THUMB: u32 = 1 << 24;
+
+/// Interval between checks of whether the algorithm has returned
+const POLL_INTERVAL_US: u32 = 50;
+
+/// DHCSR reads to wait for a halt or core register transfer to complete
+const READY_RETRIES: usize = 100;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    SWD(swd::Error),
+    /// The core did not halt or finish a register transfer
+    NotReady,
+    /// The algorithm did not return in time
+    Timeout,
+    /// The function is not provided by the algorithm
+    Unsupported,
+    /// The algorithm returned this non-zero result
+    Failed(u32),
+}
+
+impl From<swd::Error> for Error {
+    fn from(error: swd::Error) -> Self {
+        Error::SWD(error)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Functions of a CMSIS flash algorithm which can be called
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Function {
+    Init = 0,
+    UnInit = 1,
+    EraseSector = 2,
+    ProgramPage = 3,
+}
+
+/// Where a flash algorithm was loaded into target RAM and how to call it.
+///
+/// Entry points are zero when the algorithm does not provide them.
+#[derive(Copy, Clone)]
+pub struct Descriptor {
+    /// Address of the breakpoint instruction functions return to
+    pub load_address: u32,
+    pub pc_init: u32,
+    pub pc_uninit: u32,
+    pub pc_program_page: u32,
+    pub pc_erase_sector: u32,
+    pub static_base: u32,
+    pub stack_pointer: u32,
+    /// Page buffers in target RAM; the second is zero to program from one buffer
+    pub buffers: [u32; 2],
+    pub page_size: u32,
+    pub page_timeout_ms: u32,
+}
+
+impl Descriptor {
+    /// Number of bytes in the encoded descriptor
+    pub const SIZE: usize = 44;
+
+    /// Decode a descriptor from its eleven little-endian words, in field order.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        let mut words = [0; Self::SIZE / 4];
+        for (word, bytes) in words.iter_mut().zip(data.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let descriptor = Descriptor {
+            load_address: words[0],
+            pc_init: words[1],
+            pc_uninit: words[2],
+            pc_program_page: words[3],
+            pc_erase_sector: words[4],
+            static_base: words[5],
+            stack_pointer: words[6],
+            buffers: [words[7], words[8]],
+            page_size: words[9],
+            page_timeout_ms: words[10],
+        };
+        if descriptor.pc_program_page == 0
+            || descriptor.buffers[0] == 0
+            || descriptor.page_size == 0
+            || descriptor.page_size & 3 != 0
+        {
+            return None;
+        }
+        Some(descriptor)
+    }
+
+    /// Encode the descriptor as read by `from_bytes`.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let words = [
+            self.load_address,
+            self.pc_init,
+            self.pc_uninit,
+            self.pc_program_page,
+            self.pc_erase_sector,
+            self.static_base,
+            self.stack_pointer,
+            self.buffers[0],
+            self.buffers[1],
+            self.page_size,
+            self.page_timeout_ms,
+        ];
+        let mut data = [0; Self::SIZE];
+        for (bytes, word) in data.chunks_exact_mut(4).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    fn entry(&self, function: Function) -> Option<u32> {
+        let pc = match function {
+            Function::Init => self.pc_init,
+            Function::UnInit => self.pc_uninit,
+            Function::EraseSector => self.pc_erase_sector,
+            Function::ProgramPage => self.pc_program_page,
+        };
+        if pc == 0 {
+            None
+        } else {
+            Some(pc)
+        }
+    }
+}
+
+/// A flash algorithm loaded into target RAM, run on the halted core.
+pub struct FlashAlgo {
+    apsel: u8,
+    descriptor: Descriptor,
+}
+
+impl FlashAlgo {
+    pub fn new(apsel: u8, descriptor: Descriptor) -> Self {
+        FlashAlgo { apsel, descriptor }
+    }
+
+    /// Index of the MEM-AP the core is accessed through
+    pub fn apsel(&self) -> u8 {
+        self.apsel
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Enable halting debug and halt the core, ready to run functions.
+    pub fn halt(&self, mem: &mut MemAP) -> Result<()> {
+        mem.write_word(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT)?;
+        for _ in 0..READY_RETRIES {
+            if mem.read_word(DHCSR)? & DHCSR_S_HALT != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::NotReady)
+    }
+
+    /// Disable halting debug and reset the target, so it runs its new firmware.
+    pub fn reset(&self, mem: &mut MemAP) -> Result<()> {
+        mem.write_word(DHCSR, DHCSR_DBGKEY)?;
+        // The reset may prevent the write from being acknowledged
+        mem.write_word(AIRCR, AIRCR_SYSRESETREQ).ok();
+        Ok(())
+    }
+
+    /// Call `function` with `args` in R0 to R2, returning its result once it
+    /// returns within `timeout_ms`.
+    pub fn call(
+        &self,
+        mem: &mut MemAP,
+        delay: &Delay,
+        function: Function,
+        args: [u32; 3],
+        timeout_ms: u32,
+    ) -> Result<u32> {
+        self.start(mem, function, args)?;
+        self.wait(mem, delay, timeout_ms)
+    }
+
+    /// Call `function` as for `call`, doing nothing if the algorithm lacks it.
+    pub fn call_optional(
+        &self,
+        mem: &mut MemAP,
+        delay: &Delay,
+        function: Function,
+        args: [u32; 3],
+        timeout_ms: u32,
+    ) -> Result<u32> {
+        match self.call(mem, delay, function, args, timeout_ms) {
+            Err(Error::Unsupported) => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Start `function` running on the halted core with `args` in R0 to R2.
+    ///
+    /// It returns to the breakpoint at the load address, halting the core again.
+    pub fn start(&self, mem: &mut MemAP, function: Function, args: [u32; 3]) -> Result<()> {
+        let pc = self.descriptor.entry(function).ok_or(Error::Unsupported)?;
+        for (reg, &arg) in (R0..).zip(args.iter()) {
+            Self::write_core_register(mem, reg, arg)?;
+        }
+        Self::write_core_register(mem, R9, self.descriptor.static_base)?;
+        Self::write_core_register(mem, SP, self.descriptor.stack_pointer)?;
+        Self::write_core_register(mem, LR, self.descriptor.load_address | 1)?;
+        Self::write_core_register(mem, PC, pc)?;
+        Self::write_core_register(mem, XPSR, XPSR_THUMB)?;
+        mem.write_word(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN)?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout_ms` for the running function to return, halting the
+    /// core if it does not.
+    ///
+    /// Returns an error if the function's result in R0 is not zero.
+    pub fn wait(&self, mem: &mut MemAP, delay: &Delay, timeout_ms: u32) -> Result<u32> {
+        // Time spent on each check is not counted, so this waits at least the timeout
+        let mut waited_us = 0;
+        while mem.read_word(DHCSR)? & DHCSR_S_HALT == 0 {
+            if waited_us / 1000 >= timeout_ms {
+                self.halt(mem)?;
+                return Err(Error::Timeout);
+            }
+            bootload::feed_watchdog();
+            delay.delay_us(POLL_INTERVAL_US);
+            waited_us += POLL_INTERVAL_US;
+        }
+
+        match Self::read_core_register(mem, R0)? {
+            0 => Ok(0),
+            result => Err(Error::Failed(result)),
+        }
+    }
+
+    fn write_core_register(mem: &mut MemAP, reg: u32, value: u32) -> Result<()> {
+        mem.write_word(DCRDR, value)?;
+        mem.write_word(DCRSR, DCRSR_REGWNR | reg)?;
+        Self::wait_register_ready(mem)
+    }
+
+    fn read_core_register(mem: &mut MemAP, reg: u32) -> Result<u32> {
+        mem.write_word(DCRSR, reg)?;
+        Self::wait_register_ready(mem)?;
+        Ok(mem.read_word(DCRDR)?)
+    }
+
+    fn wait_register_ready(mem: &mut MemAP) -> Result<()> {
+        for _ in 0..READY_RETRIES {
+            if mem.read_word(DHCSR)? & DHCSR_S_REGRDY != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::NotReady)
+    }
+}
+
+/// Programs consecutive flash pages from data arriving in pieces.
+///
+/// Each page is written into a buffer in target RAM and programmed while the
+/// next page fills the other buffer, if the algorithm has two.
+pub struct Programmer {
+    /// Flash address of the page being filled
+    addr: u32,
+    /// Bytes of that page already written to its buffer
+    filled: u32,
+    /// Index of the buffer being filled
+    buffer: usize,
+    /// Whether a page is being programmed
+    busy: bool,
+    /// Bytes of pages programmed successfully
+    programmed: u32,
+}
+
+impl Programmer {
+    /// Start programming pages from `addr`, which must be page aligned.
+    pub fn new(addr: u32) -> Self {
+        Programmer {
+            addr,
+            filled: 0,
+            buffer: 0,
+            busy: false,
+            programmed: 0,
+        }
+    }
+
+    /// Bytes of pages programmed successfully
+    pub fn programmed(&self) -> u32 {
+        self.programmed
+    }
+
+    /// Write `data` into page buffers, programming each page once it is filled.
+    pub fn write(
+        &mut self,
+        algo: &FlashAlgo,
+        mem: &mut MemAP,
+        delay: &Delay,
+        mut data: &[u8],
+    ) -> Result<()> {
+        let page_size = algo.descriptor.page_size;
+        while !data.is_empty() {
+            let n = core::cmp::min(data.len(), (page_size - self.filled) as usize);
+            let (piece, rest) = data.split_at(n);
+            let buffer = algo.descriptor.buffers[self.buffer] + self.filled;
+            mem.write_bytes(buffer, piece)?;
+            self.filled += n as u32;
+            data = rest;
+
+            if self.filled == page_size {
+                self.program_page(algo, mem, delay)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the last page to be programmed.
+    ///
+    /// Data must have been written in whole pages.
+    pub fn finish(&mut self, algo: &FlashAlgo, mem: &mut MemAP, delay: &Delay) -> Result<()> {
+        self.wait(algo, mem, delay)
+    }
+
+    /// Start programming the filled buffer once the previous page is done.
+    fn program_page(&mut self, algo: &FlashAlgo, mem: &mut MemAP, delay: &Delay) -> Result<()> {
+        let descriptor = &algo.descriptor;
+        self.wait(algo, mem, delay)?;
+        let buffer = descriptor.buffers[self.buffer];
+        algo.start(
+            mem,
+            Function::ProgramPage,
+            [self.addr, descriptor.page_size, buffer],
+        )?;
+        self.busy = true;
+        self.addr += descriptor.page_size;
+        self.filled = 0;
+
+        if descriptor.buffers[1] != 0 {
+            self.buffer ^= 1;
+        } else {
+            // The only buffer cannot be refilled until programming finishes
+            self.wait(algo, mem, delay)?;
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self, algo: &FlashAlgo, mem: &mut MemAP, delay: &Delay) -> Result<()> {
+        if self.busy {
+            self.busy = false;
+            algo.wait(mem, delay, algo.descriptor.page_timeout_ms)?;
+            self.programmed += algo.descriptor.page_size;
+        }
+        Ok(())
+    }
+}
@@ -22,6 +22,7 @@ pub struct JTAG<'a> {
     delay: &'a Delay,
     half_period_ticks: AtomicU32,
     use_bitbang: AtomicBool,
+    clock: AtomicU32,
 }
 
 impl<'a> JTAG<'a> {
@@ -41,6 +42,7 @@ impl<'a> JTAG<'a> {
             delay,
             half_period_ticks: AtomicU32::new(10000),
             use_bitbang: AtomicBool::new(true),
+            clock: AtomicU32::new(0),
         }
     }
 
@@ -48,12 +50,20 @@ impl<'a> JTAG<'a> {
         let period = self.delay.calc_period_ticks(max_frequency);
         self.half_period_ticks.store(period / 2, Ordering::SeqCst);
 
-        if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
+        let clock = if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
             self.spi.set_prescaler(prescaler);
             self.use_bitbang.store(false, Ordering::SeqCst);
+            self.spi.base_clock() / prescaler.divisor()
         } else {
             self.use_bitbang.store(true, Ordering::SeqCst);
-        }
+            self.delay.base_clock() / (period & !1).max(1)
+        };
+        self.clock.store(clock, Ordering::SeqCst);
+    }
+
+    /// TCK frequency set by the last call to `set_clock`
+    pub fn clock(&self) -> u32 {
+        self.clock.load(Ordering::SeqCst)
     }
 
     pub fn spi_enable(&self) {
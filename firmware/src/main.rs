@@ -1,12 +1,13 @@
 #![no_std]
 #![no_main]
 
-use cortex_m_rt::{entry, pre_init};
+use cortex_m_rt::{entry, exception};
 use git_version::git_version;
 pub use hs_probe_bsp as bsp;
 use panic_rtt_target as _;
 use rtt_target::{rprintln, rtt_init_print};
 use stm32_device_signature::device_id_hex;
+use stm32ral::interrupt;
 
 const GIT_VERSION: &str = git_version!();
 
@@ -14,25 +15,16 @@ const DAP1_PACKET_SIZE: u16 = 64;
 const DAP2_PACKET_SIZE: u16 = 512;
 
 mod app;
+mod config;
 mod dap;
+mod flashalgo;
 mod jtag;
+mod memap;
+mod rtt;
+mod standalone;
 mod swd;
 mod usb;
 
-#[pre_init]
-unsafe fn pre_init() {
-    // Check if we should jump to system bootloader.
-    //
-    // When we receive the BOOTLOAD command over USB,
-    // we write a flag to a static and reset the chip,
-    // and `bootload::check()` will jump to the system
-    // memory bootloader if the flag is present.
-    //
-    // It must be called from pre_init as otherwise the
-    // flag is overwritten when statics are initialised.
-    bsp::bootload::check();
-}
-
 #[entry]
 fn main() -> ! {
     rtt_init_print!();
@@ -42,20 +34,40 @@ fn main() -> ! {
     cp.SCB.enable_icache();
 
     let rcc = bsp::rcc::RCC::new(stm32ral::rcc::RCC::take().unwrap());
+    let flash: &'static bsp::flash::Flash = cortex_m::singleton!(
+        : bsp::flash::Flash = bsp::flash::Flash::new(stm32ral::flash::FLASH::take().unwrap())
+    )
+    .unwrap();
+
+    // Load persistent configuration, which must outlive the USB device
+    let config: &'static config::Config =
+        cortex_m::singleton!(: config::Config = config::Config::load(device_id_hex())).unwrap();
 
     let usb_phy = stm32ral::usbphyc::USBPHYC::take().unwrap();
     let usb_global = stm32ral::otg_hs_global::OTG_HS_GLOBAL::take().unwrap();
     let usb_device = stm32ral::otg_hs_device::OTG_HS_DEVICE::take().unwrap();
     let usb_pwrclk = stm32ral::otg_hs_pwrclk::OTG_HS_PWRCLK::take().unwrap();
-    let mut usb = crate::usb::USB::new(usb_phy, usb_global, usb_device, usb_pwrclk);
-
-    let dma = bsp::dma::DMA::new(
-        stm32ral::dma::DMA1::take().unwrap(),
-        stm32ral::dma::DMA2::take().unwrap(),
-    );
+    // The USB stack and SWO UART are used from interrupt handlers, so must be static
+    let usb: &'static mut usb::USB = cortex_m::singleton!(
+        : usb::USB = usb::USB::new(usb_phy, usb_global, usb_device, usb_pwrclk)
+    )
+    .unwrap();
+
+    let dma: &'static bsp::dma::DMA = cortex_m::singleton!(
+        : bsp::dma::DMA = bsp::dma::DMA::new(
+            stm32ral::dma::DMA1::take().unwrap(),
+            stm32ral::dma::DMA2::take().unwrap(),
+        )
+    )
+    .unwrap();
+    let crc = bsp::crc::CRC::new(stm32ral::crc::CRC::take().unwrap());
     let spi1 = bsp::spi::SPI::new(stm32ral::spi::SPI1::take().unwrap());
     let spi2 = bsp::spi::SPI::new(stm32ral::spi::SPI2::take().unwrap());
-    let mut uart1 = bsp::uart::UART::new(stm32ral::usart::USART1::take().unwrap(), &dma);
+    let uart1: &'static bsp::uart::UART<'static> = cortex_m::singleton!(
+        : bsp::uart::UART<'static> =
+            bsp::uart::UART::new(stm32ral::usart::USART1::take().unwrap(), dma)
+    )
+    .unwrap();
 
     let _gpioa = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOA::take().unwrap());
     let gpiob = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOB::take().unwrap());
@@ -89,21 +101,61 @@ fn main() -> ! {
 
     let syst = stm32ral::syst::SYST::take().unwrap();
     let delay = bsp::delay::Delay::new(syst);
-
-    let swd = swd::SWD::new(&spi1, &pins);
-    let jtag = jtag::JTAG::new(&spi2, &dma, &pins, &delay);
-    let mut dap = dap::DAP::new(swd, jtag, &mut uart1, &pins);
+    let timer = bsp::timer::Timer::new(stm32ral::tim2::TIM2::take().unwrap());
+
+    let swd = swd::SWD::new(&spi1, dma, &pins, &delay);
+    let jtag = jtag::JTAG::new(&spi2, dma, &pins, &delay);
+    let mut dap = dap::DAP::new(
+        swd,
+        jtag,
+        uart1,
+        &pins,
+        flash,
+        &crc,
+        &delay,
+        &timer,
+        config.clone(),
+    );
 
     // Create App instance with the HAL instances
-    let mut app = app::App::new(&rcc, &dma, &pins, &spi1, &spi2, &mut usb, &mut dap, &delay);
+    let mut app = app::App::new(
+        &rcc, dma, &pins, &spi1, &spi2, usb, uart1, &mut dap, &delay, &timer, flash,
+    );
 
     rprintln!("Starting...");
 
     // Initialise application, including system peripherals
-    unsafe { app.setup(device_id_hex()) };
+    unsafe { app.setup(config) };
 
     loop {
-        // Process events
+        // Process events, sleeping while idle
         app.poll();
     }
 }
+
+#[interrupt]
+fn OTG_HS() {
+    app::usb_interrupt();
+}
+
+#[interrupt]
+fn USART1() {
+    app::swo_interrupt();
+}
+
+#[interrupt]
+fn DMA2_Stream5() {
+    app::swo_interrupt();
+}
+
+#[interrupt]
+fn DMA1_Stream3() {
+    // Only wakes `SPI::jtag_exchange` from WFI, which clears the flag itself
+}
+
+#[exception]
+fn SysTick() {
+    // Wakes from WFI, see `App::poll`, and keeps the watchdog fed once the
+    // boot trial is over
+    bsp::bootload::feed_watchdog_if_confirmed();
+}
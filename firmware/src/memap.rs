@@ -0,0 +1,280 @@
+use crate::swd::{self, APnDP, CachedRegister, DPRegister};
+use num_enum::TryFromPrimitive;
+
+/// MEM-AP register addresses within bank 0, as A[3:2]
+const CSW: u8 = 0;
+const TAR: u8 = 1;
+const DRW: u8 = 3;
+
+/// CSW AddrInc field set to increment TAR by the access size
+const CSW_ADDRINC_SINGLE: u32 = 1 << 4;
+
+/// CSW fields we set for each transfer: DeviceEn, TrInProg, AddrInc and Size
+const CSW_TRANSFER_MASK: u32 = 0xFF;
+
+/// TAR is only guaranteed to increment within 1 KB blocks
+const TAR_WRAP: u32 = 1024;
+
+/// Size of each memory access, as encoded in CSW
+#[derive(Copy, Clone, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Size {
+    U8 = 0,
+    U16 = 1,
+    U32 = 2,
+}
+
+impl Size {
+    pub fn bytes(self) -> usize {
+        1 << (self as usize)
+    }
+
+    /// Whether `addr` and `len` are multiples of the access size
+    pub fn aligned(self, addr: u32, len: usize) -> bool {
+        let mask = self.bytes() - 1;
+        (addr as usize | len) & mask == 0
+    }
+}
+
+/// Failure of an access made by `MemAP::preserving`
+#[derive(Copy, Clone, Debug)]
+pub enum PreserveError {
+    /// The access failed, but the host's AP state was restored
+    Access(swd::Error),
+    /// The host's AP state could not be restored
+    Restore(swd::Error),
+}
+
+/// Memory access through a MEM-AP.
+pub struct MemAP<'s, 'a> {
+    swd: &'s mut swd::SWD<'a>,
+    apsel: u8,
+}
+
+impl<'s, 'a> MemAP<'s, 'a> {
+    pub fn new(swd: &'s mut swd::SWD<'a>, apsel: u8) -> Self {
+        MemAP { swd, apsel }
+    }
+
+    pub fn apsel(&self) -> u8 {
+        self.apsel
+    }
+
+    /// Select bank 0 of the AP and set CSW for auto-incrementing accesses of `size`.
+    ///
+    /// Other CSW fields such as Prot are kept from the current value.
+    pub fn setup(&mut self, size: Size) -> swd::Result<()> {
+        let select = (self.apsel as u32) << 24;
+        self.swd.write_dp(DPRegister::SELECT as u8, select)?;
+
+        let csw = self.read_register(CSW, CachedRegister::CSW)?;
+        let csw = (csw & !CSW_TRANSFER_MASK) | CSW_ADDRINC_SINGLE | size as u32;
+        self.swd.write_mem_ap(CSW, csw)
+    }
+
+    /// Read `buf.len()` bytes from `addr` with accesses of `size`.
+    ///
+    /// `setup` must have been called for `size`, and `addr` and the length
+    /// must be aligned to it.
+    pub fn read(&mut self, mut addr: u32, size: Size, mut buf: &mut [u8]) -> swd::Result<()> {
+        let n = size.bytes();
+        while !buf.is_empty() {
+            let len = core::cmp::min(buf.len(), Self::wrap_len(addr));
+            let (chunk, rest) = core::mem::take(&mut buf).split_at_mut(len);
+            self.swd.write_mem_ap(TAR, addr)?;
+
+            // Each DRW read returns the data of the previous one,
+            // and the final data is read from RDBUFF.
+            self.swd.read_ap(DRW)?;
+            let count = len / n;
+            for (i, element) in chunk.chunks_exact_mut(n).enumerate() {
+                let value = if i + 1 < count {
+                    self.swd.read_ap(DRW)?
+                } else {
+                    self.swd.read_dp(DPRegister::RDBUFF as u8)?
+                };
+                let lane = 8 * ((addr as usize + i * n) & 3);
+                element.copy_from_slice(&(value >> lane).to_le_bytes()[..n]);
+            }
+
+            addr += len as u32;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to `addr` with accesses of `size`.
+    ///
+    /// `setup` must have been called for `size`, and `addr` and the length
+    /// must be aligned to it.
+    pub fn write(&mut self, mut addr: u32, size: Size, mut data: &[u8]) -> swd::Result<()> {
+        let n = size.bytes();
+        while !data.is_empty() {
+            let len = core::cmp::min(data.len(), Self::wrap_len(addr));
+            let (chunk, rest) = data.split_at(len);
+            self.swd.write_mem_ap(TAR, addr)?;
+
+            if size == Size::U32 {
+                self.swd.write_block(APnDP::AP, DRW, chunk).1?;
+            } else {
+                for (i, element) in chunk.chunks_exact(n).enumerate() {
+                    let mut value = [0; 4];
+                    value[..n].copy_from_slice(element);
+                    let lane = 8 * ((addr as usize + i * n) & 3);
+                    self.swd.write_ap(DRW, u32::from_le_bytes(value) << lane)?;
+                }
+            }
+
+            addr += len as u32;
+            data = rest;
+        }
+
+        // Writes are posted, so check the last one completed
+        self.swd.read_dp(DPRegister::RDBUFF as u8).map(|_| ())
+    }
+
+    /// Read one word from `addr`, which must be word aligned.
+    pub fn read_word(&mut self, addr: u32) -> swd::Result<u32> {
+        let mut buf = [0; 4];
+        self.setup(Size::U32)?;
+        self.read(addr, Size::U32, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Write one word to `addr`, which must be word aligned.
+    pub fn write_word(&mut self, addr: u32, value: u32) -> swd::Result<()> {
+        self.setup(Size::U32)?;
+        self.write(addr, Size::U32, &value.to_le_bytes())
+    }
+
+    /// Read `buf.len()` bytes from `addr`, using word accesses except for
+    /// unaligned bytes at either end.
+    pub fn read_bytes(&mut self, mut addr: u32, mut buf: &mut [u8]) -> swd::Result<()> {
+        while !buf.is_empty() {
+            let (size, n) = Self::split_unaligned(addr, buf.len());
+            let (piece, rest) = core::mem::take(&mut buf).split_at_mut(n);
+            self.setup(size)?;
+            self.read(addr, size, piece)?;
+            addr += n as u32;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to `addr`, using word accesses except for unaligned
+    /// bytes at either end.
+    pub fn write_bytes(&mut self, mut addr: u32, mut data: &[u8]) -> swd::Result<()> {
+        while !data.is_empty() {
+            let (size, n) = Self::split_unaligned(addr, data.len());
+            let (piece, rest) = data.split_at(n);
+            self.setup(size)?;
+            self.write(addr, size, piece)?;
+            addr += n as u32;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Run `f` without disturbing accesses set up by the host.
+    ///
+    /// The AP's CSW and TAR are restored afterwards, followed by SELECT, which
+    /// the caller must know to have been `select` before. This happens even if
+    /// `f` fails, after clearing sticky errors so nothing is left for the
+    /// host's next transfer.
+    pub fn preserving<T, F>(&mut self, select: u32, f: F) -> Result<T, PreserveError>
+    where
+        F: FnOnce(&mut Self) -> swd::Result<T>,
+    {
+        let saved = self.save_registers();
+        let result = match saved {
+            Ok(_) => f(self),
+            Err(e) => Err(e),
+        };
+
+        let restored = match result {
+            Ok(_) => Ok(()),
+            Err(_) => self.swd.clear_errors(),
+        };
+        let restored = restored.and_then(|_| {
+            // CSW and TAR are only changed once they have been saved
+            if let Ok((csw, tar)) = saved {
+                self.swd.write_mem_ap(CSW, csw)?;
+                self.swd.write_mem_ap(TAR, tar)?;
+            }
+            self.swd.write_dp(DPRegister::SELECT as u8, select)
+        });
+
+        match (result, restored) {
+            (_, Err(e)) => Err(PreserveError::Restore(e)),
+            (Err(e), Ok(())) => Err(PreserveError::Access(e)),
+            (Ok(value), Ok(())) => Ok(value),
+        }
+    }
+
+    /// Select this AP and read its CSW and TAR, for `preserving` to restore.
+    fn save_registers(&mut self) -> swd::Result<(u32, u32)> {
+        self.swd.write_dp(DPRegister::SELECT as u8, (self.apsel as u32) << 24)?;
+        let csw = self.read_register(CSW, CachedRegister::CSW)?;
+        let tar = self.read_register(TAR, CachedRegister::TAR)?;
+        Ok((csw, tar))
+    }
+
+    /// Read one word from `addr` without disturbing accesses set up by the host.
+    pub fn peek(&mut self, addr: u32, select: u32) -> Result<u32, PreserveError> {
+        self.preserving(select, |mem| mem.read_word(addr))
+    }
+
+    /// Read a bank 0 AP register, using its cached value if known.
+    fn read_register(&mut self, a: u8, reg: CachedRegister) -> swd::Result<u32> {
+        match self.swd.cached(reg) {
+            Some(value) => Ok(value),
+            None => {
+                // AP reads are posted
+                self.swd.read_ap(a)?;
+                self.swd.read_dp(DPRegister::RDBUFF as u8)
+            }
+        }
+    }
+
+    /// Read `len` bytes from `addr` in pieces of up to `buf.len()` bytes,
+    /// passing each to `f` until it returns false.
+    ///
+    /// Word accesses are used except for unaligned bytes at either end.
+    pub fn read_with<F>(
+        &mut self,
+        mut addr: u32,
+        mut len: usize,
+        buf: &mut [u8],
+        mut f: F,
+    ) -> swd::Result<()>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        while len > 0 {
+            // Keep pieces after the first word aligned
+            let n = core::cmp::min(len, buf.len() - (addr & 3) as usize);
+            self.read_bytes(addr, &mut buf[..n])?;
+            if !f(&buf[..n]) {
+                break;
+            }
+            addr += n as u32;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    /// Size and length of the first accesses of a transfer of `len` bytes at
+    /// `addr`: single bytes up to a word boundary, then whole words.
+    fn split_unaligned(addr: u32, len: usize) -> (Size, usize) {
+        if addr & 3 != 0 || len < 4 {
+            (Size::U8, core::cmp::min(len, 4 - (addr & 3) as usize))
+        } else {
+            (Size::U32, len & !3)
+        }
+    }
+
+    /// Bytes from `addr` to the next TAR auto-increment boundary
+    fn wrap_len(addr: u32) -> usize {
+        (TAR_WRAP - (addr % TAR_WRAP)) as usize
+    }
+}
@@ -0,0 +1,221 @@
+use crate::memap::MemAP;
+use crate::swd;
+
+/// ID at the start of the RTT control block, including its terminating NUL
+const ID: &[u8] = b"SEGGER RTT\0";
+
+/// Offsets within the control block
+const MAX_UP_BUFFERS: u32 = 16;
+const BUFFERS: u32 = 24;
+
+/// Size of each buffer descriptor, and offsets within it
+const DESCRIPTOR_SIZE: u32 = 24;
+const BUFFER: u32 = 4;
+const WR_OFF: u32 = 12;
+
+/// Upper bound on buffer counts, to reject a control block which is not initialised
+const MAX_BUFFERS: u32 = 32;
+
+/// How often to poll the target's buffers while idle
+pub const POLL_INTERVAL_MS: u32 = 1;
+
+/// One ring buffer of the control block
+#[derive(Copy, Clone)]
+struct Channel {
+    /// Address of the buffer descriptor
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+}
+
+impl Channel {
+    fn load(mem: &mut MemAP, descriptor: u32) -> swd::Result<Option<Self>> {
+        let mut words = [0; 8];
+        mem.read_bytes(descriptor + BUFFER, &mut words)?;
+        let buffer = u32::from_le_bytes([words[0], words[1], words[2], words[3]]);
+        let size = u32::from_le_bytes([words[4], words[5], words[6], words[7]]);
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Channel {
+            descriptor,
+            buffer,
+            size,
+        }))
+    }
+
+    /// Read the write and read offsets, or None if they are out of range.
+    fn offsets(&self, mem: &mut MemAP) -> swd::Result<Option<(u32, u32)>> {
+        let mut words = [0; 8];
+        mem.read_bytes(self.descriptor + WR_OFF, &mut words)?;
+        let wr = u32::from_le_bytes([words[0], words[1], words[2], words[3]]);
+        let rd = u32::from_le_bytes([words[4], words[5], words[6], words[7]]);
+        if wr < self.size && rd < self.size {
+            Ok(Some((wr, rd)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn set_write_offset(&self, mem: &mut MemAP, wr: u32) -> swd::Result<()> {
+        mem.write_word(self.descriptor + WR_OFF, wr)
+    }
+
+    fn set_read_offset(&self, mem: &mut MemAP, rd: u32) -> swd::Result<()> {
+        mem.write_word(self.descriptor + WR_OFF + 4, rd)
+    }
+}
+
+/// A SEGGER RTT control block in target memory, with the channels being bridged.
+pub struct RTT {
+    apsel: u8,
+    control_block: u32,
+    up: Channel,
+    down: Option<Channel>,
+}
+
+impl RTT {
+    /// Find the control block by its ID in the `len` bytes from `addr`.
+    pub fn search(mem: &mut MemAP, addr: u32, len: usize) -> swd::Result<Option<u32>> {
+        let mut matched = 0;
+        let mut offset = addr;
+        let mut found = None;
+        let mut buf = [0; crate::DAP2_PACKET_SIZE as usize];
+        mem.read_with(addr, len, &mut buf, |data| {
+            crate::bsp::bootload::feed_watchdog();
+            for &byte in data {
+                // The first byte of the ID appears nowhere else in it
+                matched = match byte {
+                    b if b == ID[matched] => matched + 1,
+                    b if b == ID[0] => 1,
+                    _ => 0,
+                };
+                offset += 1;
+                if matched == ID.len() {
+                    found = Some(offset - ID.len() as u32);
+                    return false;
+                }
+            }
+            true
+        })?;
+        Ok(found)
+    }
+
+    /// Attach to up channel `up` and down channel `down`, if any, of the control
+    /// block at `control_block`.
+    ///
+    /// Returns None if there is no initialised control block there, or it lacks
+    /// the channels.
+    pub fn attach(
+        mem: &mut MemAP,
+        control_block: u32,
+        up: u8,
+        down: Option<u8>,
+    ) -> swd::Result<Option<Self>> {
+        let mut header = [0; 24];
+        mem.read_bytes(control_block, &mut header)?;
+        let word = |offset: usize| {
+            let b = &header[offset..offset + 4];
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+        let max_up = word(MAX_UP_BUFFERS as usize);
+        let max_down = word(MAX_UP_BUFFERS as usize + 4);
+        if &header[..ID.len()] != ID || max_up > MAX_BUFFERS || max_down > MAX_BUFFERS {
+            return Ok(None);
+        }
+
+        let descriptors = control_block + BUFFERS;
+        let up = match up as u32 {
+            up if up < max_up => Channel::load(mem, descriptors + up * DESCRIPTOR_SIZE)?,
+            _ => None,
+        };
+        let down = match down.map(|down| down as u32) {
+            None => None,
+            Some(down) if down < max_down => {
+                let descriptor = descriptors + (max_up + down) * DESCRIPTOR_SIZE;
+                match Channel::load(mem, descriptor)? {
+                    Some(channel) => Some(channel),
+                    None => return Ok(None),
+                }
+            }
+            Some(_) => return Ok(None),
+        };
+
+        Ok(up.map(|up| RTT {
+            apsel: mem.apsel(),
+            control_block,
+            up,
+            down,
+        }))
+    }
+
+    /// Index of the MEM-AP the control block is accessed through
+    pub fn apsel(&self) -> u8 {
+        self.apsel
+    }
+
+    pub fn control_block(&self) -> u32 {
+        self.control_block
+    }
+
+    /// Pass new data from the up channel to `write`, which returns how many
+    /// bytes it accepted, using `buf` to hold the data.
+    pub fn poll_up<F>(&self, mem: &mut MemAP, buf: &mut [u8], write: F) -> swd::Result<()>
+    where
+        F: FnOnce(&[u8]) -> usize,
+    {
+        let (wr, rd) = match self.up.offsets(mem)? {
+            Some(offsets) => offsets,
+            None => return Ok(()),
+        };
+
+        // Data up to the write offset, or the end of the buffer if it wrapped
+        let available = if wr >= rd { wr - rd } else { self.up.size - rd };
+        let n = core::cmp::min(available as usize, buf.len());
+        if n == 0 {
+            return Ok(());
+        }
+
+        mem.read_bytes(self.up.buffer + rd, &mut buf[..n])?;
+        let sent = write(&buf[..n]) as u32;
+        if sent > 0 {
+            self.up.set_read_offset(mem, (rd + sent) % self.up.size)?;
+        }
+        Ok(())
+    }
+
+    /// Fill the down channel with data from `read`, which returns how many
+    /// bytes it wrote into the buffer it is given, out of `buf`.
+    pub fn poll_down<F>(&self, mem: &mut MemAP, buf: &mut [u8], read: F) -> swd::Result<()>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let down = match self.down {
+            Some(down) => down,
+            None => return Ok(()),
+        };
+        let (wr, rd) = match down.offsets(mem)? {
+            Some(offsets) => offsets,
+            None => return Ok(()),
+        };
+
+        // Free space up to the read offset or the end of the buffer, always
+        // leaving one byte free so a full buffer is not mistaken for empty
+        let free = if rd > wr {
+            rd - wr - 1
+        } else {
+            down.size - wr - (rd == 0) as u32
+        };
+        let n = core::cmp::min(free as usize, buf.len());
+        if n == 0 {
+            return Ok(());
+        }
+
+        let n = read(&mut buf[..n]);
+        if n == 0 {
+            return Ok(());
+        }
+        mem.write_bytes(down.buffer + wr, &buf[..n])?;
+        down.set_write_offset(mem, (wr + n as u32) % down.size)
+    }
+}
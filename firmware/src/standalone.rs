@@ -0,0 +1,242 @@
+use crate::bsp::{
+    crc::CRC,
+    flash::{self, Flash, SECTOR_ADDRESSES},
+};
+use crate::flashalgo::Descriptor;
+use core::convert::TryInto;
+use num_enum::TryFromPrimitive;
+
+/// A target image and flash algorithm are stored in flash sectors 1 to 4,
+/// between the boot stage and the firmware slots. Sector 1 holds the
+/// header followed by the algorithm, and sectors 2 to 4 hold the image.
+const FIRST_SECTOR: u8 = 1;
+const LAST_SECTOR: u8 = 4;
+const HEADER_SIZE: u32 = 256;
+const IMAGE_MAGIC: u32 = 0x4D49_5348; // "HSIM"
+const IMAGE_VERSION: u8 = 1;
+
+/// Flags byte bit making the probe program the target when it powers up
+pub const FLAG_PROGRAM_ON_BOOT: u8 = 1 << 0;
+
+// Stored header layout:
+// 0..4: magic
+// 4: version
+// 5: index of the MEM-AP the target core is accessed through
+// 6: flags
+// 7: reserved
+// 8..52: flash algorithm descriptor
+// 52..56: algorithm length
+// 56..60: target flash address of the image
+// 60..64: image length
+// 64..68: target flash sector size
+// 68..72: timeout for erasing each sector in milliseconds
+// 72..76: CRC-32 of the algorithm
+// 76..80: CRC-32 of the image
+// 80..84: CRC-32 of bytes 0..80
+const DESCRIPTOR_OFFSET: usize = 8;
+const CHECKSUM_OFFSET: usize = 80;
+const STORED_LEN: usize = CHECKSUM_OFFSET + 4;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    InvalidLength,
+    InvalidAddress,
+    /// The header has already been written since the region was erased
+    NotErased,
+    Flash(flash::Error),
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Parts of the stored image region written by the host
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Region {
+    Algorithm = 0,
+    Image = 1,
+}
+
+impl Region {
+    /// Address of the region in probe flash
+    pub fn address(self) -> u32 {
+        match self {
+            Region::Algorithm => SECTOR_ADDRESSES[FIRST_SECTOR as usize] + HEADER_SIZE,
+            Region::Image => SECTOR_ADDRESSES[FIRST_SECTOR as usize + 1],
+        }
+    }
+
+    /// Maximum length of the region in bytes
+    pub fn max_len(self) -> u32 {
+        match self {
+            Region::Algorithm => SECTOR_ADDRESSES[FIRST_SECTOR as usize + 1] - self.address(),
+            Region::Image => SECTOR_ADDRESSES[LAST_SECTOR as usize + 1] - self.address(),
+        }
+    }
+}
+
+/// A target image and the flash algorithm to program it with, stored in probe flash.
+#[derive(Copy, Clone)]
+pub struct StoredImage {
+    pub apsel: u8,
+    pub flags: u8,
+    pub descriptor: Descriptor,
+    pub algorithm_len: u32,
+    /// Target flash address the image is programmed to
+    pub address: u32,
+    pub image_len: u32,
+    pub sector_size: u32,
+    pub erase_timeout_ms: u32,
+    pub algorithm_crc: u32,
+    pub image_crc: u32,
+}
+
+impl StoredImage {
+    /// Erase the header, algorithm and image.
+    ///
+    /// This blocks for several seconds.
+    pub fn erase(flash: &Flash) -> Result<()> {
+        for sector in FIRST_SECTOR..=LAST_SECTOR {
+            crate::bsp::bootload::feed_watchdog();
+            flash.erase_sector(sector)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` into `region`, which must have been erased.
+    ///
+    /// `offset` must be word aligned.
+    pub fn write(flash: &Flash, region: Region, offset: u32, data: &[u8]) -> Result<()> {
+        if offset & 3 != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        match offset.checked_add(data.len() as u32) {
+            Some(end) if end <= region.max_len() => (),
+            _ => return Err(Error::InvalidLength),
+        }
+        flash.program(region.address() + offset, data)?;
+        Ok(())
+    }
+
+    /// Compute the CRCs of the written algorithm and image, then write the
+    /// header describing them, after which they are used.
+    pub fn commit(&mut self, flash: &Flash, crc: &CRC) -> Result<()> {
+        if self.algorithm_len == 0
+            || self.algorithm_len > Region::Algorithm.max_len()
+            || self.image_len == 0
+            || self.image_len > Region::Image.max_len()
+            || self.sector_size == 0
+        {
+            return Err(Error::InvalidLength);
+        }
+        // The image is programmed from its start in whole pages and sectors
+        if self.address % self.descriptor.page_size != 0 || self.address % self.sector_size != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        if Flash::read(Self::address(), STORED_LEN)
+            .iter()
+            .any(|&b| b != 0xFF)
+        {
+            return Err(Error::NotErased);
+        }
+
+        self.algorithm_crc = Self::crc(crc, self.algorithm());
+        self.image_crc = Self::crc(crc, self.image());
+
+        let mut stored = [0u8; STORED_LEN];
+        stored[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        stored[4] = IMAGE_VERSION;
+        stored[5] = self.apsel;
+        stored[6] = self.flags;
+        stored[DESCRIPTOR_OFFSET..DESCRIPTOR_OFFSET + Descriptor::SIZE]
+            .copy_from_slice(&self.descriptor.to_bytes());
+        let words = [
+            self.algorithm_len,
+            self.address,
+            self.image_len,
+            self.sector_size,
+            self.erase_timeout_ms,
+            self.algorithm_crc,
+            self.image_crc,
+        ];
+        let fields = DESCRIPTOR_OFFSET + Descriptor::SIZE;
+        for (bytes, word) in stored[fields..CHECKSUM_OFFSET]
+            .chunks_exact_mut(4)
+            .zip(words.iter())
+        {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        let checksum = Self::crc(crc, &stored[..CHECKSUM_OFFSET]);
+        stored[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+
+        flash.program(Self::address(), &stored)?;
+        Ok(())
+    }
+
+    /// Load the header from flash, if a valid one has been committed.
+    ///
+    /// The stored algorithm and image are not checked; see `verify`.
+    pub fn load(crc: &CRC) -> Option<Self> {
+        let stored = Flash::read(Self::address(), STORED_LEN);
+        let word = |offset: usize| {
+            u32::from_le_bytes(stored[offset..offset + 4].try_into().unwrap())
+        };
+        if word(0) != IMAGE_MAGIC
+            || stored[4] != IMAGE_VERSION
+            || word(CHECKSUM_OFFSET) != Self::crc(crc, &stored[..CHECKSUM_OFFSET])
+        {
+            return None;
+        }
+
+        let descriptor = Descriptor::from_bytes(&stored[DESCRIPTOR_OFFSET..])?;
+        let fields = DESCRIPTOR_OFFSET + Descriptor::SIZE;
+        Some(StoredImage {
+            apsel: stored[5],
+            flags: stored[6],
+            descriptor,
+            algorithm_len: word(fields),
+            address: word(fields + 4),
+            image_len: word(fields + 8),
+            sector_size: word(fields + 12),
+            erase_timeout_ms: word(fields + 16),
+            algorithm_crc: word(fields + 20),
+            image_crc: word(fields + 24),
+        })
+    }
+
+    /// Check the stored algorithm and image match the CRCs in the header.
+    pub fn verify(&self, crc: &CRC) -> bool {
+        Self::crc(crc, self.algorithm()) == self.algorithm_crc
+            && Self::crc(crc, self.image()) == self.image_crc
+    }
+
+    pub fn program_on_boot(&self) -> bool {
+        self.flags & FLAG_PROGRAM_ON_BOOT != 0
+    }
+
+    /// Flash algorithm code, loaded into target RAM at the descriptor's load address
+    pub fn algorithm(&self) -> &'static [u8] {
+        let len = core::cmp::min(self.algorithm_len, Region::Algorithm.max_len());
+        Flash::read(Region::Algorithm.address(), len as usize)
+    }
+
+    pub fn image(&self) -> &'static [u8] {
+        let len = core::cmp::min(self.image_len, Region::Image.max_len());
+        Flash::read(Region::Image.address(), len as usize)
+    }
+
+    fn address() -> u32 {
+        SECTOR_ADDRESSES[FIRST_SECTOR as usize]
+    }
+
+    fn crc(crc: &CRC, data: &[u8]) -> u32 {
+        crc.reset();
+        crc.update(data);
+        crc.finish()
+    }
+}
@@ -1,8 +1,16 @@
 // Copyright 2019-2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
-use crate::bsp::{gpio::Pins, spi::SPI};
-use num_enum::IntoPrimitive;
+use crate::bsp::{
+    delay::Delay,
+    dma::DMA,
+    gpio::Pins,
+    spi::{SampleEdge, SPI},
+};
+use crate::DAP2_PACKET_SIZE;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 #[derive(Copy, Clone, Debug)]
 pub enum Error {
@@ -24,15 +32,76 @@ pub enum DPRegister {
     RDBUFF = 3,
 }
 
+/// DP TARGETSEL shares its address with RDBUFF, but is write-only.
+const DP_TARGETSEL: u8 = 3;
+
+/// DP ABORT shares its address with DPIDR, but is write-only.
+const DP_ABORT: u8 = 0;
+
+/// ABORT value clearing every sticky error flag and aborting any AP transaction.
+const ABORT_CLEAR_ALL: u32 = 0x1E;
+
+/// CTRL/STAT system and debug power-up requests, and their acknowledgements.
+const CTRLSTAT_PWRUPREQ: u32 = (1 << 30) | (1 << 28);
+const CTRLSTAT_PWRUPACK: u32 = (1 << 31) | (1 << 29);
+
+/// CTRL/STAT reads to wait for power-up to be acknowledged.
+const POWER_UP_RETRIES: usize = 100;
+
+/// SELECT fields choosing the AP register bank: APSEL and APBANKSEL.
+const SELECT_AP_BANK_MASK: u32 = 0xFF00_00F0;
+
+/// Registers whose last written value is cached, so writing it again can be skipped.
+#[derive(Copy, Clone, Debug)]
+pub enum CachedRegister {
+    SELECT = 0,
+    CSW = 1,
+    TAR = 2,
+}
+
+const CACHED_REGISTERS: usize = 3;
+
+/// Counts of writes to each `CachedRegister`, and how many were skipped.
+#[derive(Copy, Clone, Default)]
+pub struct CacheStats {
+    pub writes: [u32; CACHED_REGISTERS],
+    pub elided: [u32; CACHED_REGISTERS],
+}
+
+/// Maximum number of words in a prebuilt block write stream.
+const BLOCK_WORDS: usize = DAP2_PACKET_SIZE as usize / 4;
+
+/// Stream bytes per word of a block write: WDATA, parity and trailing idle, next request.
+const BLOCK_STRIDE: usize = 6;
+
+/// Parity sample delays tried by `calibrate_sampling`, in CPU cycles.
+const PARITY_DELAYS: [u32; 4] = [0, 4, 16, 64];
+
+/// DPIDR reads per setting in `calibrate_sampling`, all of which must succeed.
+const CALIBRATION_READS: usize = 16;
+
 pub struct SWD<'a> {
     spi: &'a SPI,
+    dma: &'a DMA,
     pins: &'a Pins<'a>,
+    delay: &'a Delay,
+    half_period_ticks: AtomicU32,
+    use_bitbang: AtomicBool,
+    clock: AtomicU32,
 
     wait_retries: usize,
+    recovery: bool,
+    target: Cell<Option<u32>>,
+    select: Cell<Option<u32>>,
+    cache: Cell<[Option<u32>; CACHED_REGISTERS]>,
+    cache_stats: Cell<CacheStats>,
+    sample_point: SamplePoint,
+    parity_delay: u32,
+    stream: [u8; BLOCK_WORDS * BLOCK_STRIDE],
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum APnDP {
     DP = 0,
     AP = 1,
@@ -48,6 +117,45 @@ impl From<bool> for APnDP {
     }
 }
 
+/// When read data and ACKs are sampled, counted from the SWCLK rising edge
+/// after which the target changes SWDIO.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
+pub enum SamplePoint {
+    /// On the next rising edge, a full period later
+    Rising = 0,
+    /// On the falling edge, half a period later
+    Falling = 1,
+    /// On the falling edge after next, one and a half periods later
+    LateFalling = 2,
+    /// On the rising edge after next, two periods later
+    LateRising = 3,
+}
+
+impl SamplePoint {
+    /// All sample points, in the order `calibrate_sampling` tries them
+    const ALL: [SamplePoint; 4] = [
+        SamplePoint::Rising,
+        SamplePoint::LateFalling,
+        SamplePoint::LateRising,
+        SamplePoint::Falling,
+    ];
+
+    /// SPI clock edge to sample on
+    fn edge(self) -> SampleEdge {
+        match self {
+            SamplePoint::Rising | SamplePoint::LateRising => SampleEdge::Rising,
+            SamplePoint::Falling | SamplePoint::LateFalling => SampleEdge::Falling,
+        }
+    }
+
+    /// Whether each bit is received one SWCLK cycle later than it is sent, to
+    /// allow for a round trip delay longer than a period.
+    fn late(self) -> bool {
+        matches!(self, SamplePoint::LateFalling | SamplePoint::LateRising)
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 enum RnW {
@@ -77,21 +185,53 @@ impl ACK {
 }
 
 impl<'a> SWD<'a> {
-    pub fn new(spi: &'a SPI, pins: &'a Pins) -> Self {
+    pub fn new(spi: &'a SPI, dma: &'a DMA, pins: &'a Pins, delay: &'a Delay) -> Self {
         SWD {
             spi,
+            dma,
             pins,
+            delay,
+            half_period_ticks: AtomicU32::new(10000),
+            use_bitbang: AtomicBool::new(false),
+            clock: AtomicU32::new(0),
             wait_retries: 8,
+            recovery: false,
+            target: Cell::new(None),
+            select: Cell::new(None),
+            cache: Cell::new([None; CACHED_REGISTERS]),
+            cache_stats: Cell::new(CacheStats::default()),
+            sample_point: SamplePoint::Rising,
+            parity_delay: 0,
+            stream: [0; BLOCK_WORDS * BLOCK_STRIDE],
         }
     }
 
+    /// Set the SWD clock, bit-banging SWD if it is slower than the SPI can go.
+    ///
+    /// Returns false if the requested clock is zero.
     pub fn set_clock(&self, max_frequency: u32) -> bool {
-        if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
+        if max_frequency == 0 {
+            return false;
+        }
+
+        let period = self.delay.calc_period_ticks(max_frequency);
+        self.half_period_ticks.store(period / 2, Ordering::SeqCst);
+
+        let clock = if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
             self.spi.set_prescaler(prescaler);
-            true
+            self.use_bitbang.store(false, Ordering::SeqCst);
+            self.spi.base_clock() / prescaler.divisor()
         } else {
-            false
-        }
+            self.use_bitbang.store(true, Ordering::SeqCst);
+            self.delay.base_clock() / (period & !1).max(1)
+        };
+        self.clock.store(clock, Ordering::SeqCst);
+        true
+    }
+
+    /// SWCLK frequency set by the last call to `set_clock`
+    pub fn clock(&self) -> u32 {
+        self.clock.load(Ordering::SeqCst)
     }
 
     pub fn spi_enable(&self) {
@@ -106,6 +246,211 @@ impl<'a> SWD<'a> {
         self.wait_retries = wait_retries;
     }
 
+    /// Enable or disable recovery from protocol errors.
+    ///
+    /// When enabled, a transfer failing with a PROTOCOL or unknown ACK or bad
+    /// parity triggers a line reset, re-selection of the multidrop target if
+    /// any, a DPIDR read, clearing of sticky errors through ABORT and
+    /// restoring the last SELECT value written, after which the transfer is
+    /// retried once. AP reads are not retried, because the data of the
+    /// previous posted read is lost with the reset; the bus is still
+    /// recovered for following transfers and the original error returned.
+    pub fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    pub fn recovery(&self) -> bool {
+        self.recovery
+    }
+
+    /// Forget the multidrop target and SELECT value, for instance after
+    /// connecting or sending a sequence which deselects the target.
+    pub fn reset_state(&self) {
+        self.target.set(None);
+        self.select.set(None);
+        self.invalidate_cache();
+    }
+
+    /// Forget all cached register values, so the next write to each is sent.
+    pub fn invalidate_cache(&self) {
+        self.cache.set([None; CACHED_REGISTERS]);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats.get()
+    }
+
+    /// Last value written to `reg`, if still known.
+    pub fn cached(&self, reg: CachedRegister) -> Option<u32> {
+        self.cache.get()[reg as usize]
+    }
+
+    pub fn reset_cache_stats(&self) {
+        self.cache_stats.set(CacheStats::default());
+    }
+
+    /// Set when read data and ACKs are sampled, and the delay in CPU cycles
+    /// before sampling the parity bit of read data.
+    ///
+    /// Only applies to SPI transfers; bit-banged reads always sample half a
+    /// period after SWCLK falls.
+    pub fn set_sampling(&mut self, point: SamplePoint, parity_delay: u32) {
+        self.sample_point = point;
+        self.parity_delay = parity_delay;
+    }
+
+    pub fn sampling(&self) -> (SamplePoint, u32) {
+        (self.sample_point, self.parity_delay)
+    }
+
+    /// Find sampling settings which reliably read DPIDR.
+    ///
+    /// The current settings are kept if they work, otherwise each sample point
+    /// and parity delay is tried in turn and the first working one applied.
+    /// Returns the DPIDR value, or None if no setting worked, in which case
+    /// the previous settings are restored.
+    pub fn calibrate_sampling(&mut self) -> Option<u32> {
+        // Recovery would hide the errors we are looking for
+        let recovery = self.recovery;
+        self.recovery = false;
+        let dpidr = self.find_sampling();
+        self.recovery = recovery;
+        dpidr
+    }
+
+    fn find_sampling(&mut self) -> Option<u32> {
+        let previous = self.sampling();
+        if let Some(dpidr) = self.check_sampling() {
+            return Some(dpidr);
+        }
+
+        for &point in SamplePoint::ALL.iter() {
+            for &delay in PARITY_DELAYS.iter() {
+                self.set_sampling(point, delay);
+                if let Some(dpidr) = self.check_sampling() {
+                    return Some(dpidr);
+                }
+            }
+        }
+
+        self.set_sampling(previous.0, previous.1);
+        None
+    }
+
+    /// Read DPIDR repeatedly, returning its value if every read agrees.
+    fn check_sampling(&self) -> Option<u32> {
+        let dpidr = self.read_dp(DPRegister::DPIDR as u8).ok()?;
+        for _ in 1..CALIBRATION_READS {
+            if self.read_dp(DPRegister::DPIDR as u8).ok()? != dpidr {
+                return None;
+            }
+        }
+        Some(dpidr)
+    }
+
+    /// Base clock of the SPI, from which SWCLK is divided
+    pub fn base_clock(&self) -> u32 {
+        self.spi.base_clock()
+    }
+
+    /// Read DPIDR and the IDR of AP `apsel`, returning both.
+    ///
+    /// Leaves SELECT pointing at the AP's IDR bank.
+    pub fn read_ids(&self, apsel: u8) -> Result<(u32, u32)> {
+        let dpidr = self.read_dp(DPRegister::DPIDR as u8)?;
+        self.write_dp(DPRegister::SELECT as u8, ((apsel as u32) << 24) | (0xF << 4))?;
+        // IDR is at 0xFC, the final register of bank 0xF. The AP read is posted.
+        self.read_ap(3)?;
+        let idr = self.read_dp(DPRegister::RDBUFF as u8)?;
+        Ok((dpidr, idr))
+    }
+
+    /// Perform a line reset: 56 clocks with SWDIO high followed by idle cycles.
+    pub fn line_reset(&self) {
+        self.invalidate_cache();
+        if self.use_bitbang.load(Ordering::SeqCst) {
+            let last = self.bitbang_mode();
+            let last = self.bitbang_tx(0xFFFF_FFFF, 32, last);
+            let last = self.bitbang_tx(0xFFFF_FFFF, 24, last);
+            self.bitbang_tx(0, 4, last);
+            self.spi_mode();
+        } else {
+            for _ in 0..7 {
+                self.spi.tx8(0xFF);
+            }
+            self.idle_low();
+            self.spi.wait_busy();
+        }
+    }
+
+    /// Write DP TARGETSEL to select one target on a multidrop bus,
+    /// which must directly follow a line reset.
+    ///
+    /// No target drives the ACK of a TARGETSEL write, so it is not checked.
+    pub fn write_targetsel(&self, target: u32) {
+        let req = Self::make_request(APnDP::DP, RnW::W, DP_TARGETSEL);
+        let parity = target.count_ones() & 1;
+
+        if self.use_bitbang.load(Ordering::SeqCst) {
+            let last = self.bitbang_mode();
+            let last = self.bitbang_tx(req as u32, 8, last);
+            self.pins.spi1_mosi.set_mode_input();
+            let (_, last) = self.bitbang_rx(5, last);
+            self.pins.spi1_mosi.set_low().set_mode_output();
+            let last = self.bitbang_tx(target, 32, last);
+            self.bitbang_tx(parity, 8, last);
+            self.spi_mode();
+        } else {
+            self.spi.tx8(req);
+            self.spi.wait_busy();
+            self.spi.drain();
+            self.pins.swd_rx();
+
+            // Clock out turnaround, ACK and turnaround with the bus undriven
+            self.spi.rx5();
+            self.pins.swd_tx();
+
+            self.spi.swd_wdata_phase(target, parity as u8);
+            self.spi.wait_busy();
+        }
+    }
+
+    /// Multidrop target selected by `reset_and_select`, if any.
+    pub fn target(&self) -> Option<u32> {
+        self.target.get()
+    }
+
+    /// Line reset then select `target` on a multidrop bus, if given.
+    ///
+    /// Returns DPIDR, which must be read to leave the reset state.
+    pub fn reset_and_select(&self, target: Option<u32>) -> Result<u32> {
+        self.target.set(target);
+        self.line_reset();
+        if let Some(target) = target {
+            self.write_targetsel(target);
+        }
+        self.read_dp(DPRegister::DPIDR as u8)
+    }
+
+    /// Clear sticky errors and abort any AP transaction by writing DP ABORT.
+    pub fn clear_errors(&self) -> Result<()> {
+        self.write_dp(DP_ABORT, ABORT_CLEAR_ALL)
+    }
+
+    /// Request system and debug power-up.
+    ///
+    /// Returns false if it was not acknowledged.
+    pub fn power_up(&self) -> Result<bool> {
+        self.write_dp(DPRegister::CTRLSTAT as u8, CTRLSTAT_PWRUPREQ)?;
+        for _ in 0..POWER_UP_RETRIES {
+            let ctrlstat = self.read_dp(DPRegister::CTRLSTAT as u8)?;
+            if ctrlstat & CTRLSTAT_PWRUPACK == CTRLSTAT_PWRUPACK {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub fn idle_low(&self) {
         self.spi.tx4(0x0);
     }
@@ -122,9 +467,177 @@ impl<'a> SWD<'a> {
         self.read(APnDP::AP, a)
     }
 
+    pub fn write_ap(&self, a: u8, data: u32) -> Result<()> {
+        self.write(APnDP::AP, a, data)
+    }
+
     pub fn read(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        let result = self.read_recover(apndp, a);
+        match result {
+            // Reading DRW increments TAR
+            Ok(_) if self.is_drw(apndp, a) => self.invalidate(CachedRegister::TAR),
+            Ok(_) => (),
+            Err(_) => self.invalidate_cache(),
+        }
+        result
+    }
+
+    /// Write a register, skipping a write to SELECT which would not change it.
+    ///
+    /// AP writes are always sent: bank 0 offsets 0x0 and 0x4 are only CSW and
+    /// TAR on a MEM-AP, and other APs may act on every write to them.
+    pub fn write(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        self.write_cached(apndp, a, data, false)
+    }
+
+    /// Write register `a` of a MEM-AP selected in SELECT, skipping the write
+    /// if it would not change a cached CSW or TAR value.
+    pub fn write_mem_ap(&self, a: u8, data: u32) -> Result<()> {
+        self.write_cached(APnDP::AP, a, data, true)
+    }
+
+    fn write_cached(&self, apndp: APnDP, a: u8, data: u32, mem_ap: bool) -> Result<()> {
+        let cached = self.cached_register(apndp, a);
+        if let Some(reg) = cached {
+            let mut stats = self.cache_stats.get();
+            stats.writes[reg as usize] = stats.writes[reg as usize].wrapping_add(1);
+            let elide = (mem_ap || apndp == APnDP::DP)
+                && self.cache.get()[reg as usize] == Some(data);
+            if elide {
+                stats.elided[reg as usize] = stats.elided[reg as usize].wrapping_add(1);
+            }
+            self.cache_stats.set(stats);
+            if elide {
+                return Ok(());
+            }
+        }
+
+        let result = self.write_recover(apndp, a, data);
+        match result {
+            Ok(()) => self.update_cache(apndp, a, cached, data),
+            Err(_) => self.invalidate_cache(),
+        }
+        result
+    }
+
+    /// Which cached register a write to `a` would change, if its value is tracked.
+    ///
+    /// CSW and TAR are only tracked while SELECT is known to point at bank 0 of an AP.
+    fn cached_register(&self, apndp: APnDP, a: u8) -> Option<CachedRegister> {
+        match apndp {
+            APnDP::DP if a == DPRegister::SELECT as u8 => Some(CachedRegister::SELECT),
+            APnDP::DP => None,
+            APnDP::AP => {
+                let select = self.cache.get()[CachedRegister::SELECT as usize]?;
+                match (select & 0xF0, a) {
+                    (0, 0) => Some(CachedRegister::CSW),
+                    (0, 1) => Some(CachedRegister::TAR),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Whether `a` is a MEM-AP DRW access, which increments TAR.
+    fn is_drw(&self, apndp: APnDP, a: u8) -> bool {
+        // Without a known SELECT this may be DRW of any AP
+        match (apndp, self.cache.get()[CachedRegister::SELECT as usize]) {
+            (APnDP::AP, Some(select)) => a == 3 && select & 0xF0 == 0,
+            (APnDP::AP, None) => true,
+            (APnDP::DP, _) => false,
+        }
+    }
+
+    /// Track the effect of a successful write on the cached registers.
+    fn update_cache(&self, apndp: APnDP, a: u8, cached: Option<CachedRegister>, data: u32) {
+        let mut cache = self.cache.get();
+        match (apndp, cached) {
+            (APnDP::DP, Some(reg)) => {
+                // CSW and TAR belong to the previously selected AP
+                let previous = cache[reg as usize];
+                if previous.map(|p| (p ^ data) & SELECT_AP_BANK_MASK) != Some(0) {
+                    cache[CachedRegister::CSW as usize] = None;
+                    cache[CachedRegister::TAR as usize] = None;
+                }
+                cache[reg as usize] = Some(data);
+            }
+            // ABORT, and CTRL/STAT writes which may power down the debug domain
+            (APnDP::DP, None) => cache = [None; CACHED_REGISTERS],
+            (APnDP::AP, Some(reg)) => cache[reg as usize] = Some(data),
+            (APnDP::AP, None) if self.is_drw(apndp, a) => {
+                cache[CachedRegister::TAR as usize] = None;
+            }
+            (APnDP::AP, None) => (),
+        }
+        self.cache.set(cache);
+    }
+
+    fn invalidate(&self, reg: CachedRegister) {
+        let mut cache = self.cache.get();
+        cache[reg as usize] = None;
+        self.cache.set(cache);
+    }
+
+    fn read_recover(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        let result = self.read_retry(apndp, a);
+        match result {
+            Err(e) if self.recoverable(e) => {
+                self.recover()?;
+                match apndp {
+                    APnDP::DP => self.read_retry(apndp, a),
+                    APnDP::AP => result,
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn write_recover(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        match self.write_retry(apndp, a, data) {
+            Err(e) if self.recoverable(e) => {
+                self.recover()?;
+                self.write_retry(apndp, a, data)
+            }
+            result => result,
+        }
+    }
+
+    /// Whether `error` should trigger `recover`.
+    fn recoverable(&self, error: Error) -> bool {
+        self.recovery
+            && matches!(
+                error,
+                Error::AckProtocol | Error::AckUnknown(_) | Error::BadParity
+            )
+    }
+
+    /// Resynchronise with the target after a protocol error.
+    ///
+    /// Sticky errors are cleared before SELECT is restored, since targets
+    /// may FAULT DP writes other than to ABORT while they are set.
+    fn recover(&self) -> Result<()> {
+        self.line_reset();
+        if let Some(target) = self.target.get() {
+            self.write_targetsel(target);
+        }
+        self.read_retry(APnDP::DP, DPRegister::DPIDR as u8)?;
+        self.write_retry(APnDP::DP, DP_ABORT, ABORT_CLEAR_ALL)?;
+        if let Some(select) = self.select.get() {
+            self.write_retry(APnDP::DP, DPRegister::SELECT as u8, select)?;
+        }
+        Ok(())
+    }
+
+    /// Read, retrying while the target answers WAIT.
+    fn read_retry(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        let bitbang = self.use_bitbang.load(Ordering::SeqCst);
         for _ in 0..self.wait_retries {
-            match self.read_inner(apndp, a) {
+            let result = if bitbang {
+                self.bitbang_read_inner(apndp, a)
+            } else {
+                self.read_inner(apndp, a)
+            };
+            match result {
                 Err(Error::AckWait) => continue,
                 x => return x,
             }
@@ -132,31 +645,112 @@ impl<'a> SWD<'a> {
         Err(Error::AckWait)
     }
 
-    pub fn write(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+    /// Write, retrying while the target answers WAIT.
+    fn write_retry(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        let bitbang = self.use_bitbang.load(Ordering::SeqCst);
         for _ in 0..self.wait_retries {
-            match self.write_inner(apndp, a, data) {
+            let result = if bitbang {
+                self.bitbang_write_inner(apndp, a, data)
+            } else {
+                self.write_inner(apndp, a, data)
+            };
+            match result {
                 Err(Error::AckWait) => continue,
+                Ok(()) => {
+                    if matches!(apndp, APnDP::DP) && a == DPRegister::SELECT as u8 {
+                        self.select.set(Some(data));
+                    }
+                    return Ok(());
+                }
                 x => return x,
             }
         }
         Err(Error::AckWait)
     }
 
+    /// Write each little-endian word in `data` to the same register.
+    ///
+    /// Returns the number of words written, and the error which stopped
+    /// the block early if any.
+    pub fn write_block(&mut self, apndp: APnDP, a: u8, data: &[u8]) -> (usize, Result<()>) {
+        let (written, result) = self.write_block_words(apndp, a, data);
+
+        // Streamed writes to SELECT bypass `write_retry`, so record the last
+        // one written for `recover`
+        if written > 0 && matches!(apndp, APnDP::DP) && a == DPRegister::SELECT as u8 {
+            let w = &data[(written - 1) * 4..written * 4];
+            self.select.set(Some(u32::from_le_bytes([w[0], w[1], w[2], w[3]])));
+        }
+
+        // The streamed words bypass the cache, so forget what they changed
+        match (result, self.cached_register(apndp, a)) {
+            (Err(_), _) | (Ok(()), Some(CachedRegister::SELECT)) => self.invalidate_cache(),
+            (Ok(()), Some(reg)) => self.invalidate(reg),
+            (Ok(()), None) => self.update_cache(apndp, a, None, 0),
+        }
+        (written, result)
+    }
+
+    fn write_block_words(&mut self, apndp: APnDP, a: u8, data: &[u8]) -> (usize, Result<()>) {
+        // Slow enough clocks gain nothing from streaming, so just write each word.
+        if self.use_bitbang.load(Ordering::SeqCst) {
+            for (idx, word) in data.chunks_exact(4).enumerate() {
+                let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                if let Err(e) = self.write(apndp, a, value) {
+                    return (idx, Err(e));
+                }
+            }
+            return (data.len() / 4, Ok(()));
+        }
+
+        let words = data.len() / 4;
+        let mut written = 0;
+        while written < words {
+            let end = core::cmp::min(words, written + BLOCK_WORDS);
+            let (n, result) = self.write_block_inner(apndp, a, &data[written * 4..end * 4]);
+            written += n;
+            match result {
+                Ok(()) => (),
+                Err(e) if self.recoverable(e) => {
+                    // Recover and retry the failed word, then resume streaming.
+                    let w = &data[written * 4..written * 4 + 4];
+                    let value = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+                    let result = self
+                        .recover()
+                        .and_then(|_| self.write_retry(apndp, a, value));
+                    if let Err(e) = result {
+                        return (written, Err(e));
+                    }
+                    written += 1;
+                }
+                Err(e) => return (written, Err(e)),
+            }
+        }
+        (written, Ok(()))
+    }
+
     fn read_inner(&self, apndp: APnDP, a: u8) -> Result<u32> {
         let req = Self::make_request(apndp, RnW::R, a);
         self.spi.tx8(req);
         self.spi.wait_busy();
         self.spi.drain();
         self.pins.swd_rx();
+        self.sample_edge_begin();
 
-        // 1 clock for turnaround and 3 for ACK
-        let ack = self.spi.rx4() >> 1;
+        // 1 clock for turnaround and 3 for ACK, then 1 more when sampling late
+        let late = self.sample_point.late();
+        let ack = if late {
+            self.spi.rx5() >> 2
+        } else {
+            self.spi.rx4() >> 1
+        };
         match ACK::try_ok(ack as u8) {
             Ok(_) => (),
             Err(e) => {
                 // On non-OK ACK, target has released the bus but
                 // is still expecting a turnaround clock before
                 // the next request, and we need to take over the bus.
+                self.sample_edge_end();
                 self.pins.swd_tx();
                 self.idle_low();
                 return Err(e);
@@ -165,8 +759,9 @@ impl<'a> SWD<'a> {
 
         // Read 8x4=32 bits of data and 8x1=8 bits for parity+turnaround+trailing.
         // Doing a batch of 5 8-bit reads is the quickest option as we keep the FIFO hot.
-        let (data, parity) = self.spi.swd_rdata_phase(self.pins);
+        let (data, parity) = self.spi.swd_rdata_phase(self.pins, self.parity_delay, late);
         let parity = (parity & 1) as u32;
+        self.sample_edge_end();
 
         // Back to driving SWDIO to ensure it doesn't float high
         self.pins.swd_tx();
@@ -186,9 +781,11 @@ impl<'a> SWD<'a> {
         self.spi.wait_busy();
         self.spi.drain();
         self.pins.swd_rx();
+        self.sample_edge_begin();
 
         // 1 clock for turnaround and 3 for ACK and 1 for turnaround
-        let ack = (self.spi.rx5() >> 1) & 0b111;
+        let ack = (self.spi.rx5() >> self.ack_shift()) & 0b111;
+        self.sample_edge_end();
         self.pins.swd_tx();
         match ACK::try_ok(ack as u8) {
             Ok(_) => (),
@@ -207,6 +804,192 @@ impl<'a> SWD<'a> {
         Ok(())
     }
 
+    fn write_block_inner(&mut self, apndp: APnDP, a: u8, data: &[u8]) -> (usize, Result<()>) {
+        let req = Self::make_request(apndp, RnW::W, a);
+        let words = data.len() / 4;
+
+        // Prebuild the stream for the whole block. Each word is followed by its
+        // parity and trailing idle bits, exactly as in `write_inner`, and then by
+        // the request for the next write, so everything we drive onto SWDIO
+        // between two ACK phases goes out in a single DMA transfer.
+        let stream = self.stream.chunks_exact_mut(BLOCK_STRIDE);
+        for (word, stream) in data.chunks_exact(4).zip(stream) {
+            let parity = word.iter().map(|b| b.count_ones()).sum::<u32>() & 1;
+            stream[..4].copy_from_slice(word);
+            stream[4] = parity as u8;
+            stream[5] = req;
+        }
+
+        let mut request_sent = false;
+        for idx in 0..words {
+            let start = idx * BLOCK_STRIDE;
+            if !request_sent {
+                self.spi.tx8(req);
+                self.spi.wait_busy();
+                self.spi.drain();
+            }
+            self.pins.swd_rx();
+            self.sample_edge_begin();
+
+            // 1 clock for turnaround and 3 for ACK and 1 for turnaround
+            let ack = (self.spi.rx5() >> self.ack_shift()) & 0b111;
+            self.sample_edge_end();
+            self.pins.swd_tx();
+            match ACK::try_ok(ack as u8) {
+                Ok(_) => {
+                    // The final word has no following request.
+                    let last = idx == words - 1;
+                    let len = if last { BLOCK_STRIDE - 1 } else { BLOCK_STRIDE };
+                    self.spi.swd_tx_dma(self.dma, &self.stream[start..start + len]);
+                    request_sent = !last;
+                }
+                Err(Error::AckWait) => {
+                    // Retry this word on its own, then resume streaming from the
+                    // next word. The cache was not updated by the streamed words,
+                    // so must not elide it; `write_block` invalidates it after.
+                    let w = &self.stream[start..start + 4];
+                    let value = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+                    if let Err(e) = self.write_retry(apndp, a, value) {
+                        return (idx, Err(e));
+                    }
+                    request_sent = false;
+                }
+                Err(e) => return (idx, Err(e)),
+            }
+        }
+
+        (words, Ok(()))
+    }
+
+    /// Position of the first ACK bit in the write turnaround, ACK and
+    /// turnaround bits, which arrive a bit later when sampling late.
+    #[inline(always)]
+    fn ack_shift(&self) -> u32 {
+        1 + self.sample_point.late() as u32
+    }
+
+    /// Switch to the configured sample edge before receiving from the target.
+    #[inline(always)]
+    fn sample_edge_begin(&self) {
+        let edge = self.sample_point.edge();
+        if edge != SampleEdge::Rising {
+            self.switch_sample_edge(edge);
+        }
+    }
+
+    /// Return to sampling on the rising edge, which transmitting relies on.
+    #[inline(always)]
+    fn sample_edge_end(&self) {
+        if self.sample_point.edge() != SampleEdge::Rising {
+            self.switch_sample_edge(SampleEdge::Rising);
+        }
+    }
+
+    fn switch_sample_edge(&self, edge: SampleEdge) {
+        // Hold SWCLK high while the SPI is disabled
+        self.spi.wait_busy();
+        self.pins.spi1_clk.set_high();
+        self.pins.swd_clk_direct();
+        self.spi.set_swd_sample_edge(edge);
+        self.pins.swd_clk_spi();
+    }
+
+    fn bitbang_read_inner(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        let req = Self::make_request(apndp, RnW::R, a);
+        let last = self.bitbang_mode();
+        let last = self.bitbang_tx(req as u32, 8, last);
+        self.pins.spi1_mosi.set_mode_input();
+
+        // 1 clock for turnaround and 3 for ACK
+        let (ack, last) = self.bitbang_rx(4, last);
+        if let Err(e) = ACK::try_ok((ack >> 1) as u8) {
+            // Take over the bus again, clocking the turnaround the target expects.
+            self.pins.spi1_mosi.set_low().set_mode_output();
+            self.bitbang_tx(0, 4, last);
+            self.spi_mode();
+            return Err(e);
+        }
+
+        // 32 bits of data, 1 bit of parity and 1 clock for turnaround
+        let (data, last) = self.bitbang_rx(32, last);
+        let (parity, last) = self.bitbang_rx(2, last);
+
+        // Back to driving SWDIO, with the same trailing idle cycles as the SPI path
+        self.pins.spi1_mosi.set_low().set_mode_output();
+        self.bitbang_tx(0, 4, last);
+        self.spi_mode();
+
+        if (parity & 1) == (data.count_ones() & 1) {
+            Ok(data)
+        } else {
+            Err(Error::BadParity)
+        }
+    }
+
+    fn bitbang_write_inner(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        let req = Self::make_request(apndp, RnW::W, a);
+        let parity = data.count_ones() & 1;
+        let last = self.bitbang_mode();
+        let last = self.bitbang_tx(req as u32, 8, last);
+        self.pins.spi1_mosi.set_mode_input();
+
+        // 1 clock for turnaround and 3 for ACK and 1 for turnaround
+        let (ack, last) = self.bitbang_rx(5, last);
+        self.pins.spi1_mosi.set_low().set_mode_output();
+        if let Err(e) = ACK::try_ok(((ack >> 1) & 0b111) as u8) {
+            self.spi_mode();
+            return Err(e);
+        }
+
+        // 32 bits of data, then parity and 7 trailing idle bits as on the SPI path
+        let last = self.bitbang_tx(data, 32, last);
+        self.bitbang_tx(parity, 8, last);
+        self.spi_mode();
+
+        Ok(())
+    }
+
+    /// Clock out the `bits` least significant bits of `data`, LSb first.
+    fn bitbang_tx(&self, mut data: u32, bits: usize, mut last: u32) -> u32 {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        for _ in 0..bits {
+            self.pins.spi1_mosi.set_bool(data & 1 != 0);
+            data >>= 1;
+            self.pins.spi1_clk.set_low();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.spi1_clk.set_high();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+        last
+    }
+
+    /// Clock in `bits` bits, LSb first, sampling just before each rising edge.
+    fn bitbang_rx(&self, bits: usize, mut last: u32) -> (u32, u32) {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let mut data = 0;
+        for i in 0..bits {
+            self.pins.spi1_clk.set_low();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            data |= (self.pins.spi1_miso.is_high() as u32) << i;
+            self.pins.spi1_clk.set_high();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+        (data, last)
+    }
+
+    /// Take SWCLK and SWDIO away from the SPI, returning the current delay timestamp.
+    fn bitbang_mode(&self) -> u32 {
+        self.pins.spi1_clk.set_high().set_mode_output();
+        self.pins.spi1_mosi.set_low().set_mode_output();
+        self.delay.get_current()
+    }
+
+    /// Return SWCLK and SWDIO to the SPI.
+    fn spi_mode(&self) {
+        self.pins.spi1_clk.set_mode_alternate();
+        self.pins.spi1_mosi.set_mode_alternate();
+    }
+
     fn make_request(apndp: APnDP, rnw: RnW, a: u8) -> u8 {
         let req = 1 | ((apndp as u8) << 1) | ((rnw as u8) << 2) | (a << 3) | (1 << 7);
         let parity = (req.count_ones() & 1) as u8;
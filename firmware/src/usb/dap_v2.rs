@@ -9,6 +9,7 @@ pub struct CmsisDapV2<'a, B: UsbBus> {
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
     trace_ep: EndpointIn<'a, B>,
+    write_busy: bool,
     trace_busy: bool,
 }
 
@@ -41,10 +42,15 @@ impl<B: UsbBus> CmsisDapV2<'_, B> {
                     0xff,
                 )
                 .expect("alloc_ep failed"),
+            write_busy: false,
             trace_busy: false,
         }
     }
 
+    pub fn interface(&self) -> InterfaceNumber {
+        self.interface
+    }
+
     pub fn process(&mut self) -> Option<Request> {
         let mut buf = [0u8; DAP2_PACKET_SIZE as usize];
         match self.read_ep.read(&mut buf) {
@@ -57,7 +63,13 @@ impl<B: UsbBus> CmsisDapV2<'_, B> {
         if data.len() > self.write_ep.max_packet_size() as usize {
             return Err(UsbError::BufferOverflow);
         }
-        self.write_ep.write(&data).map(|_| ())
+        self.write_ep.write(&data).map(|_| ())?;
+        self.write_busy = true;
+        Ok(())
+    }
+
+    pub fn write_busy(&self) -> bool {
+        self.write_busy
     }
 
     pub fn trace_busy(&self) -> bool {
@@ -94,11 +106,14 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapV2<'_, B> {
     }
 
     fn reset(&mut self) {
+        self.write_busy = false;
         self.trace_busy = false;
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
-        if addr == self.trace_ep.address() {
+        if addr == self.write_ep.address() {
+            self.write_busy = false;
+        } else if addr == self.trace_ep.address() {
             self.trace_busy = false;
         }
     }
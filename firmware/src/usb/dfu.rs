@@ -1,3 +1,5 @@
+use hs_probe_bsp::flash::Flash;
+use hs_probe_bsp::image::{self, ImageHeader, Slot};
 use usb_device::class_prelude::*;
 use usb_device::control::{Recipient, RequestType};
 use usb_device::Result;
@@ -13,71 +15,394 @@ mod request {
     pub const DFU_ABORT: u8 = 6;
 }
 
-pub struct DfuRuntime {
+/// Maximum DNLOAD/UPLOAD block size, limited by the control endpoint buffer.
+const TRANSFER_SIZE: u16 = 256;
+
+/// bwPollTimeout reported while erasing the target slot, in milliseconds.
+const ERASE_POLL_TIMEOUT: u32 = 2000;
+
+/// bwPollTimeout reported while programming a block, in milliseconds.
+const PROGRAM_POLL_TIMEOUT: u32 = 10;
+
+#[derive(Copy, Clone, PartialEq)]
+#[repr(u8)]
+enum State {
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0x00,
+    ErrFile = 0x02,
+    ErrErase = 0x04,
+    ErrProg = 0x06,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrStalledPkt = 0x0F,
+}
+
+/// Flash work requested by the host.
+///
+/// Erasing a slot takes seconds, so this is carried out by `App::poll` rather
+/// than in the USB interrupt, and the result reported with `Dfu::complete`.
+pub enum Operation {
+    /// Program a downloaded block, erasing the target slot before the first block
+    Program {
+        flash: &'static Flash,
+        target: Slot,
+        offset: u32,
+        block: [u8; TRANSFER_SIZE as usize],
+        len: usize,
+    },
+    /// Reset to let the boot stage try the new image
+    Reset,
+}
+
+impl Operation {
+    /// Carry out the operation, returning the status to report to the host.
+    pub fn run(&self) -> Status {
+        match self {
+            Operation::Program {
+                flash,
+                target,
+                offset,
+                block,
+                len,
+            } => {
+                if *offset == 0 {
+                    hs_probe_bsp::bootload::feed_watchdog();
+                    if flash.erase_sector(target.sector()).is_err() {
+                        return Status::ErrErase;
+                    }
+                }
+                match flash.program(target.address() + offset, &block[..*len]) {
+                    Ok(()) => Status::Ok,
+                    Err(_) => Status::ErrProg,
+                }
+            }
+            Operation::Reset => {
+                // Give the USB core time to send the final DFU_GETSTATUS response
+                // before resetting into the boot stage to try the new image.
+                cortex_m::asm::delay(1_000_000);
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+    }
+}
+
+/// DFU 1.1 interface for updating the probe firmware.
+///
+/// Downloaded images are written to the inactive firmware slot and have their
+/// CRC verified during manifestation, after which the probe resets and the boot
+/// stage tries the new image. Upload reads back the running image.
+///
+/// A DFU_DETACH request instead resets into the STM32 system bootloader, which
+/// remains available as a fallback for recovery.
+pub struct Dfu {
     interface: InterfaceNumber,
+    name: StringIndex,
+    flash: &'static Flash,
+    state: State,
+    status: Status,
+    /// Most recent DNLOAD block, waiting to be programmed.
+    block: [u8; TRANSFER_SIZE as usize],
+    block_len: usize,
+    /// Number of bytes downloaded or uploaded so far.
+    offset: u32,
+    /// Slot which downloaded images are written to.
+    target: Slot,
+    /// Whether an `Operation` has been handed out and not yet completed.
+    running: bool,
 }
 
-impl DfuRuntime {
-    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> DfuRuntime {
-        DfuRuntime {
+impl Dfu {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, flash: &'static Flash) -> Dfu {
+        Dfu {
             interface: alloc.interface(),
+            name: alloc.string(),
+            flash,
+            state: State::DfuIdle,
+            status: Status::Ok,
+            block: [0; TRANSFER_SIZE as usize],
+            block_len: 0,
+            offset: 0,
+            target: Slot::current().map(Slot::other).unwrap_or(Slot::A),
+            running: false,
         }
     }
+
+    /// Take the flash operation requested by the host, if any.
+    ///
+    /// Erasing and programming are requested after the DFU_GETSTATUS response
+    /// telling the host how long to wait, and only one operation is handed out
+    /// until it is completed.
+    pub fn operation(&mut self) -> Option<Operation> {
+        if self.running {
+            return None;
+        }
+        let operation = match self.state {
+            State::DnBusy => Operation::Program {
+                flash: self.flash,
+                target: self.target,
+                offset: self.offset,
+                block: self.block,
+                len: self.block_len,
+            },
+            State::ManifestWaitReset => Operation::Reset,
+            _ => return None,
+        };
+        self.running = true;
+        Some(operation)
+    }
+
+    /// Record the result of the operation taken with `operation`.
+    ///
+    /// The result is dropped if the host reset or aborted the transfer meanwhile.
+    pub fn complete(&mut self, status: Status) {
+        if !core::mem::replace(&mut self.running, false) || self.state != State::DnBusy {
+            return;
+        }
+        self.state = if status == Status::Ok {
+            self.offset += self.block_len as u32;
+            self.block_len = 0;
+            State::DnloadIdle
+        } else {
+            self.error(status)
+        };
+    }
+
+    /// Check the downloaded image is built for the target slot, is newer than
+    /// the running image, is complete, and its CRC matches the header.
+    fn verify(&self) -> core::result::Result<ImageHeader, Status> {
+        let header = self.target.header().ok_or(Status::ErrFile)?;
+        let current = Slot::current().and_then(Slot::header);
+        if current.map_or(false, |current| header.version <= current.version) {
+            return Err(Status::ErrFile);
+        }
+        if image::HEADER_SIZE + header.length > self.offset || !header.verify(self.target) {
+            return Err(Status::ErrVerify);
+        }
+        Ok(header)
+    }
+
+    fn error(&mut self, status: Status) -> State {
+        self.status = status;
+        self.offset = 0;
+        self.block_len = 0;
+        State::Error
+    }
+
+    fn get_status<B: UsbBus>(&mut self, xfer: ControlIn<B>) {
+        let mut poll_timeout = 0;
+        match self.state {
+            State::DnloadSync => {
+                poll_timeout = if self.offset == 0 {
+                    ERASE_POLL_TIMEOUT
+                } else {
+                    PROGRAM_POLL_TIMEOUT
+                };
+                self.state = State::DnBusy;
+            }
+            State::ManifestSync => match self.verify() {
+                Ok(_) => self.state = State::Manifest,
+                Err(status) => self.state = self.error(status),
+            },
+            _ => (),
+        }
+
+        let poll_timeout = poll_timeout.to_le_bytes();
+        xfer.accept_with(&[
+            self.status as u8,
+            poll_timeout[0],
+            poll_timeout[1],
+            poll_timeout[2],
+            self.state as u8,
+            0, // iString
+        ])
+        .ok();
+
+        // We are not manifestation tolerant, so once the host has seen
+        // dfuMANIFEST we reset to start the new firmware.
+        if self.state == State::Manifest {
+            self.state = State::ManifestWaitReset;
+        }
+    }
+
+    fn upload<B: UsbBus>(&mut self, xfer: ControlIn<B>) {
+        if self.state == State::DfuIdle {
+            self.offset = 0;
+            self.state = State::UploadIdle;
+        }
+
+        // Read back the running image including its header, or the whole
+        // slot for an unheadered development build.
+        let slot = Slot::current().unwrap_or(Slot::A);
+        let size = slot
+            .header()
+            .map_or(image::SLOT_SIZE, |header| image::HEADER_SIZE + header.length);
+
+        let requested = xfer.request().length as u32;
+        let len = core::cmp::min(requested, size - self.offset);
+        let data = Flash::read(slot.address() + self.offset, len as usize);
+        self.offset += len;
+
+        // A short block tells the host the upload is complete
+        if len < requested {
+            self.offset = 0;
+            self.state = State::DfuIdle;
+        }
+
+        xfer.accept_with(data).ok();
+    }
+
+    fn download<B: UsbBus>(&mut self, xfer: ControlOut<B>) {
+        let data = xfer.data();
+
+        if data.is_empty() {
+            // A zero-length download ends the transfer and begins manifestation
+            if self.state == State::DnloadIdle {
+                self.state = State::ManifestSync;
+                xfer.accept().ok();
+            } else {
+                self.stall(xfer);
+            }
+            return;
+        }
+
+        if self.state == State::DfuIdle {
+            self.offset = 0;
+        }
+
+        if data.len() > self.block.len() {
+            self.stall(xfer);
+        } else if self.offset + data.len() as u32 > image::SLOT_SIZE {
+            self.state = self.error(Status::ErrAddress);
+            xfer.reject().ok();
+        } else {
+            self.block[..data.len()].copy_from_slice(data);
+            self.block_len = data.len();
+            self.state = State::DnloadSync;
+            xfer.accept().ok();
+        }
+    }
+
+    fn stall<B: UsbBus>(&mut self, xfer: ControlOut<B>) {
+        self.state = self.error(Status::ErrStalledPkt);
+        xfer.reject().ok();
+    }
+
+    fn is_dfu_request(&self, req: &control::Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+    }
 }
 
-impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+impl<B: UsbBus> UsbClass<B> for Dfu {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface(self.interface, 0xFE, 1, 1)?;
+        // Interface protocol 2: DFU mode
+        writer.interface_alt(self.interface, 0, 0xFE, 1, 2, Some(self.name))?;
+
+        let transfer_size = TRANSFER_SIZE.to_le_bytes();
 
         // DFU Functional Descriptor
         writer.write(
             0x21, // Functional descriptor type
             &[
-                0x0F, // bmAttributes
+                0x0B, // bmAttributes: bitWillDetach, bitCanUpload, bitCanDnload
                 0xFF, 0x00, // wDetachTimeOut
-                0x08, 0x00, // wTransferSize
-                0x00, 0x01, // bcdDFUVersion
+                transfer_size[0], transfer_size[1], // wTransferSize
+                0x10, 0x01, // bcdDFUVersion
             ],
         )?;
 
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.name {
+            // Tell the user which build of the firmware to download
+            Some(match self.target {
+                Slot::A => "HS-Probe Firmware (slot A image)",
+                Slot::B => "HS-Probe Firmware (slot B image)",
+            })
+        } else {
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        // A new image has been downloaded and we are about to reset
+        if self.state == State::ManifestWaitReset {
+            return;
+        }
+        self.state = State::DfuIdle;
+        self.status = Status::Ok;
+        self.offset = 0;
+        self.block_len = 0;
+        self.running = false;
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
-        let req = xfer.request();
-        if !(req.request_type == RequestType::Class
-            && req.recipient == Recipient::Interface
-            && req.index == u8::from(self.interface) as u16)
-        {
+        if !self.is_dfu_request(xfer.request()) {
             return;
         }
 
-        match req.request {
-            request::DFU_GETSTATUS => {
-                xfer.accept_with_static(&[0x00; 6]).ok();
+        let request = xfer.request().request;
+        match request {
+            request::DFU_GETSTATUS => self.get_status(xfer),
+            request::DFU_GETSTATE => {
+                xfer.accept_with(&[self.state as u8]).ok();
+            }
+            request::DFU_UPLOAD
+                if self.state == State::DfuIdle || self.state == State::UploadIdle =>
+            {
+                self.upload(xfer);
             }
             _ => {
+                self.state = self.error(Status::ErrStalledPkt);
                 xfer.reject().ok();
             }
         }
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        let req = xfer.request();
-        if !(req.request_type == RequestType::Class
-            && req.recipient == Recipient::Interface
-            && req.index == u8::from(self.interface) as u16)
-        {
+        if !self.is_dfu_request(xfer.request()) {
             return;
         }
 
-        match req.request {
+        let request = xfer.request().request;
+        match request {
             request::DFU_DETACH => {
                 hs_probe_bsp::bootload::bootload();
             }
-            _ => {
-                xfer.reject().ok();
+            request::DFU_DNLOAD
+                if self.state == State::DfuIdle || self.state == State::DnloadIdle =>
+            {
+                self.download(xfer);
+            }
+            request::DFU_CLRSTATUS if self.state == State::Error => {
+                self.state = State::DfuIdle;
+                self.status = Status::Ok;
+                xfer.accept().ok();
+            }
+            request::DFU_ABORT => {
+                self.state = State::DfuIdle;
+                self.offset = 0;
+                self.block_len = 0;
+                self.running = false;
+                xfer.accept().ok();
             }
+            _ => self.stall(xfer),
         }
     }
 }
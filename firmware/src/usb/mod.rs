@@ -1,5 +1,7 @@
 use crate::app::Request;
+use crate::config::Config;
 use crate::DAP2_PACKET_SIZE;
+use hs_probe_bsp::flash::Flash;
 use hs_probe_bsp::otg_hs::{UsbBus, UsbBusType};
 use hs_probe_bsp::rcc::Clocks;
 use stm32ral::{otg_hs_device, otg_hs_global, otg_hs_pwrclk, usbphyc};
@@ -10,11 +12,14 @@ use usbd_serial::SerialPort;
 mod dap_v1;
 mod dap_v2;
 mod dfu;
+mod webusb;
 mod winusb;
 
 use dap_v1::CmsisDapV1;
 use dap_v2::CmsisDapV2;
-use dfu::DfuRuntime;
+use dfu::Dfu;
+pub use dfu::{Operation as DfuOperation, Status as DfuStatus};
+use webusb::WebUsb;
 use winusb::MicrosoftDescriptors;
 
 struct UninitializedUSB {
@@ -28,10 +33,11 @@ struct InitializedUSB {
     device: UsbDevice<'static, UsbBusType>,
     device_state: UsbDeviceState,
     winusb: MicrosoftDescriptors,
+    webusb: WebUsb,
     dap_v1: CmsisDapV1<'static, UsbBusType>,
     dap_v2: CmsisDapV2<'static, UsbBusType>,
     serial: SerialPort<'static, UsbBusType>,
-    dfu: DfuRuntime,
+    dfu: Dfu,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -87,7 +93,7 @@ impl USB {
     }
 
     /// Initialise the USB peripheral ready to start processing packets
-    pub fn setup(&mut self, clocks: &Clocks, serial_string: &'static str) {
+    pub fn setup(&mut self, clocks: &Clocks, config: &'static Config, flash: &'static Flash) {
         let state = core::mem::replace(&mut self.state, State::Initializing);
         if let State::Uninitialized(usb) = state {
             cortex_m::interrupt::free(|_| unsafe {
@@ -103,26 +109,29 @@ impl USB {
                 USB_BUS = Some(usb_bus);
                 let usb_bus = USB_BUS.as_ref().unwrap();
 
-                let winusb = MicrosoftDescriptors;
                 let dap_v1 = CmsisDapV1::new(&usb_bus);
                 let dap_v2 = CmsisDapV2::new(&usb_bus);
+                let winusb = MicrosoftDescriptors::new(dap_v2.interface());
+                let webusb = WebUsb;
                 let serial = SerialPort::new(&usb_bus);
-                let dfu = DfuRuntime::new(&usb_bus);
-
-                let device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x4853))
-                    .manufacturer("Probe-rs development team")
-                    .product("HS-Probe with CMSIS-DAP Support")
-                    .serial_number(serial_string)
-                    .device_class(0)
-                    .max_packet_size_0(64)
-                    .max_power(500)
-                    .build();
+                let dfu = Dfu::new(&usb_bus, flash);
+
+                let device =
+                    UsbDeviceBuilder::new(&usb_bus, UsbVidPid(config.vid(), config.pid()))
+                        .manufacturer("Probe-rs development team")
+                        .product(config.product())
+                        .serial_number(config.serial())
+                        .device_class(0)
+                        .max_packet_size_0(64)
+                        .max_power(500)
+                        .build();
                 let device_state = device.state();
 
                 let usb = InitializedUSB {
                     device,
                     device_state,
                     winusb,
+                    webusb,
                     dap_v1,
                     dap_v2,
                     serial,
@@ -140,15 +149,19 @@ impl USB {
     /// Call this function when a USB interrupt occurs.
     ///
     /// Returns Some(Request) if a new request has been received
-    /// from the host.
+    /// from the host. New DAP commands are only read if `read_commands`
+    /// is true, otherwise they wait in their endpoints for a later call.
+    /// Serial input is discarded if `discard_serial` is true, otherwise it
+    /// waits to be read with `serial_read`.
     ///
     /// This function will clear the interrupt bits of all interrupts
     /// it processes; if any are unprocessed the USB interrupt keeps
     /// triggering until all are processed.
-    pub fn interrupt(&mut self) -> Option<Request> {
+    pub fn interrupt(&mut self, read_commands: bool, discard_serial: bool) -> Option<Request> {
         let usb = self.state.as_initialized_mut();
         if usb.device.poll(&mut [
             &mut usb.winusb,
+            &mut usb.webusb,
             &mut usb.dap_v1,
             &mut usb.dap_v2,
             &mut usb.serial,
@@ -157,10 +170,29 @@ impl USB {
             let old_state = usb.device_state;
             let new_state = usb.device.state();
             usb.device_state = new_state;
-            if (old_state != new_state) && (new_state != UsbDeviceState::Configured) {
-                return Some(Request::Suspend);
+            if old_state != new_state {
+                match (old_state, new_state) {
+                    (_, UsbDeviceState::Suspend) => return Some(Request::Suspend),
+                    (UsbDeviceState::Suspend, UsbDeviceState::Configured) => {
+                        return Some(Request::Resume)
+                    }
+                    (UsbDeviceState::Suspend, _) | (UsbDeviceState::Configured, _) => {
+                        return Some(Request::Reset)
+                    }
+                    _ => (),
+                }
             }
 
+            // Discard data from the serial interface unless it is in use
+            if discard_serial {
+                let mut buf = [0; DAP2_PACKET_SIZE as usize];
+                let _ = usb.serial.read(&mut buf);
+            }
+        }
+
+        // Commands may be waiting from an earlier interrupt,
+        // so check for them even if there was no new USB event.
+        if read_commands && usb.device_state == UsbDeviceState::Configured {
             let r = usb.dap_v1.process();
             if r.is_some() {
                 return r;
@@ -170,14 +202,22 @@ impl USB {
             if r.is_some() {
                 return r;
             }
-
-            // Discard data from the serial interface
-            let mut buf = [0; DAP2_PACKET_SIZE as usize];
-            let _ = usb.serial.read(&mut buf);
         }
         None
     }
 
+    /// Take the flash operation requested over DFU, to be run outside the interrupt
+    pub fn dfu_operation(&mut self) -> Option<DfuOperation> {
+        let usb = self.state.as_initialized_mut();
+        usb.dfu.operation()
+    }
+
+    /// Report the result of the operation taken with `dfu_operation`
+    pub fn dfu_complete(&mut self, status: DfuStatus) {
+        let usb = self.state.as_initialized_mut();
+        usb.dfu.complete(status);
+    }
+
     /// Transmit a DAP report back over the DAPv1 HID interface
     pub fn dap1_reply(&mut self, data: &[u8]) {
         let usb = self.state.as_initialized_mut();
@@ -194,6 +234,24 @@ impl USB {
             .expect("DAPv2 EP write failed");
     }
 
+    /// Check if the DAPv2 reply endpoint is still transmitting the previous reply
+    pub fn dap2_reply_is_busy(&self) -> bool {
+        let usb = self.state.as_initialized();
+        usb.dap_v2.write_busy()
+    }
+
+    /// Queue `data` for the serial port, returning how many bytes were accepted
+    pub fn serial_write(&mut self, data: &[u8]) -> usize {
+        let usb = self.state.as_initialized_mut();
+        usb.serial.write(data).unwrap_or(0)
+    }
+
+    /// Read input from the serial port into `buf`, returning how many bytes were read
+    pub fn serial_read(&mut self, buf: &mut [u8]) -> usize {
+        let usb = self.state.as_initialized_mut();
+        usb.serial.read(buf).unwrap_or(0)
+    }
+
     /// Check if SWO endpoint is currently busy transmitting data
     pub fn dap2_swo_is_busy(&self) -> bool {
         let usb = self.state.as_initialized();
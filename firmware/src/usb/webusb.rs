@@ -0,0 +1,92 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::descriptor::capability_type;
+use usb_device::Result;
+
+/// Vendor request code for WebUSB requests, advertised in the BOS platform capability.
+const WEBUSB_VENDOR_CODE: u8 = b'C';
+
+/// wIndex of the WebUSB GET_URL request.
+const WEBUSB_REQUEST_GET_URL: u16 = 2;
+
+const WEBUSB_URL_DESCRIPTOR_TYPE: u8 = 3;
+
+/// URL index of the landing page, the only URL we provide.
+const LANDING_PAGE_INDEX: u8 = 1;
+
+/// Landing page shown by the browser when the probe is plugged in.
+///
+/// Override at build time by setting `HS_PROBE_WEBUSB_URL`.
+const LANDING_PAGE_URL: &str = match option_env!("HS_PROBE_WEBUSB_URL") {
+    Some(url) => url,
+    None => "https://github.com/probe-rs/hs-probe-firmware",
+};
+
+/// BOS platform capability announcing WebUSB support.
+const WEBUSB_PLATFORM_CAPABILITY: [u8; 21] = [
+    0x00, // bReserved
+    // PlatformCapabilityUUID: {3408B638-09A9-47A0-8BFD-A0768815B665}
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+    0x00, 0x01, // bcdVersion: 1.00
+    WEBUSB_VENDOR_CODE, // bVendorCode
+    LANDING_PAGE_INDEX, // iLandingPage
+];
+
+/// WebUSB descriptors, allowing browsers to find the probe and claim the
+/// CMSIS-DAP v2 interface.
+pub struct WebUsb;
+
+impl WebUsb {
+    /// Write the URL descriptor for the landing page into `buf`.
+    ///
+    /// Returns None if the URL does not fit in a descriptor.
+    fn url_descriptor(buf: &mut [u8; 255]) -> Option<&[u8]> {
+        let (scheme, url) = if let Some(url) = LANDING_PAGE_URL.strip_prefix("https://") {
+            (1, url)
+        } else if let Some(url) = LANDING_PAGE_URL.strip_prefix("http://") {
+            (0, url)
+        } else {
+            (255, LANDING_PAGE_URL)
+        };
+
+        let len = 3 + url.len();
+        if len > buf.len() {
+            return None;
+        }
+
+        buf[0] = len as u8;
+        buf[1] = WEBUSB_URL_DESCRIPTOR_TYPE;
+        buf[2] = scheme;
+        buf[3..len].copy_from_slice(url.as_bytes());
+        Some(&buf[..len])
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for WebUsb {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        writer.capability(capability_type::PLATFORM, &WEBUSB_PLATFORM_CAPABILITY)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type != RequestType::Vendor
+            || req.recipient != Recipient::Device
+            || req.request != WEBUSB_VENDOR_CODE
+        {
+            return;
+        }
+
+        let mut buf = [0; 255];
+        match Self::url_descriptor(&mut buf) {
+            Some(descriptor)
+                if req.index == WEBUSB_REQUEST_GET_URL
+                    && req.value == LANDING_PAGE_INDEX as u16 =>
+            {
+                xfer.accept_with(descriptor).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -2,9 +2,18 @@ use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
 use usb_device::class_prelude::*;
 use usb_device::control::RequestType;
+use usb_device::descriptor::capability_type;
+use usb_device::Result;
 
 const GET_OS_FEATURE: u8 = b'A';
 
+/// Vendor request code for reading the MS OS 2.0 descriptor set,
+/// advertised to the host in the BOS platform capability.
+const MS_OS_20_VENDOR_CODE: u8 = b'B';
+
+/// wIndex of the vendor request for the MS OS 2.0 descriptor set.
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+
 #[allow(non_snake_case)]
 #[repr(u16)]
 #[derive(TryFromPrimitive)]
@@ -13,21 +22,21 @@ pub enum OSFeatureDescriptorType {
     Properties = 5,
 }
 
-const MS_COMPATIBLE_ID_DESCRIPTOR: [u8; 40] = [
+const MS_COMPATIBLE_ID_DESCRIPTOR_TEMPLATE: [u8; 40] = [
     0x28, 0x00, 0x00, 0x00, // Length 40 bytes
     0x00, 0x01, // Version
     0x04, 0x00, // Compatibility ID Descriptor index
     0x01, // Number of sections
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Reserved: 7 bytes
-    0x01, // Interface Number
+    0x00, // Interface Number, filled in from the CMSIS-DAP v2 interface
     0x01, // Reserved
     b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, // Compatible ID: 8 bytes ASCII
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Sub-Compatible ID: 8 bytes ASCII
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Reserved: 6 bytes
 ];
 
-const IF2_MS_PROPERTIES_OS_DESCRIPTOR: [u8; 142] = [
-    0x8e, 0x00, 0x00, 0x00, // Length, 40 bytes
+const MS_PROPERTIES_OS_DESCRIPTOR: [u8; 142] = [
+    0x8e, 0x00, 0x00, 0x00, // Length, 142 bytes
     0x00, 0x01, // Version
     0x05, 0x00, // wIndex: properties
     0x01, 0x00, // wCount: a single property
@@ -43,33 +52,120 @@ const IF2_MS_PROPERTIES_OS_DESCRIPTOR: [u8; 142] = [
     0, b'6', 0, b'3', 0, b'7', 0, b'7', 0, b'6', 0, b'}', 0, 0, 0,
 ];
 
-pub struct MicrosoftDescriptors;
+const MS_OS_20_DESCRIPTOR_SET_LEN: u16 = 178;
+
+/// Offset of bFirstInterface in the function subset header below.
+const MS_OS_20_FIRST_INTERFACE_OFFSET: usize = 22;
+
+const MS_OS_20_DESCRIPTOR_SET_TEMPLATE: [u8; MS_OS_20_DESCRIPTOR_SET_LEN as usize] = [
+    // Descriptor set header
+    0x0a, 0x00, // wLength
+    0x00, 0x00, // wDescriptorType: MS_OS_20_SET_HEADER_DESCRIPTOR
+    0x00, 0x00, 0x03, 0x06, // dwWindowsVersion: Windows 8.1
+    0xb2, 0x00, // wTotalLength: 178 bytes
+    // Configuration subset header
+    0x08, 0x00, // wLength
+    0x01, 0x00, // wDescriptorType: MS_OS_20_SUBSET_HEADER_CONFIGURATION
+    0x00, // bConfigurationValue
+    0x00, // bReserved
+    0xa8, 0x00, // wTotalLength: 168 bytes
+    // Function subset header
+    0x08, 0x00, // wLength
+    0x02, 0x00, // wDescriptorType: MS_OS_20_SUBSET_HEADER_FUNCTION
+    0x00, // bFirstInterface, filled in from the CMSIS-DAP v2 interface
+    0x00, // bReserved
+    0xa0, 0x00, // wSubsetLength: 160 bytes
+    // Compatible ID descriptor
+    0x14, 0x00, // wLength
+    0x03, 0x00, // wDescriptorType: MS_OS_20_FEATURE_COMPATBLE_ID
+    b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, // CompatibleID
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // SubCompatibleID
+    // Registry property descriptor
+    0x84, 0x00, // wLength
+    0x04, 0x00, // wDescriptorType: MS_OS_20_FEATURE_REG_PROPERTY
+    0x07, 0x00, // wPropertyDataType: REG_MULTI_SZ
+    0x2a, 0x00, // wPropertyNameLength
+    b'D', 0, b'e', 0, b'v', 0, b'i', 0, b'c', 0, b'e', 0, b'I', 0, b'n', 0, b't', 0, b'e', 0, b'r',
+    0, b'f', 0, b'a', 0, b'c', 0, b'e', 0, b'G', 0, b'U', 0, b'I', 0, b'D', 0, b's', 0, 0, 0,
+    0x50, 0x00, // wPropertyDataLength
+    b'{', 0, b'C', 0, b'D', 0, b'B', 0, b'3', 0, b'B', 0, b'5', 0, b'A', 0, b'D', 0, b'-', 0, b'2',
+    0, b'9', 0, b'3', 0, b'B', 0, b'-', 0, b'4', 0, b'6', 0, b'6', 0, b'3', 0, b'-', 0, b'A', 0,
+    b'A', 0, b'3', 0, b'6', 0, b'-', 0, b'1', 0, b'A', 0, b'A', 0, b'E', 0, b'4', 0, b'6', 0, b'4',
+    0, b'6', 0, b'3', 0, b'7', 0, b'7', 0, b'6', 0, b'}', 0, 0, 0, 0, 0,
+];
+
+/// BOS platform capability pointing Windows at the MS OS 2.0 descriptor set.
+const MS_OS_20_PLATFORM_CAPABILITY: [u8; 25] = [
+    0x00, // bReserved
+    // PlatformCapabilityUUID: {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+    0x00, 0x00, 0x03, 0x06, // dwWindowsVersion: Windows 8.1
+    MS_OS_20_DESCRIPTOR_SET_LEN as u8,
+    (MS_OS_20_DESCRIPTOR_SET_LEN >> 8) as u8, // wMSOSDescriptorSetTotalLength
+    MS_OS_20_VENDOR_CODE, // bMS_VendorCode
+    0x00, // bAltEnumCode
+];
+
+/// Microsoft OS descriptors binding the CMSIS-DAP v2 interface to WinUSB.
+///
+/// Windows 8.1 and newer read the MS OS 2.0 descriptor set advertised in the BOS
+/// descriptor; older versions fall back to the MS OS 1.0 descriptors.
+pub struct MicrosoftDescriptors {
+    interface: u8,
+    compatible_id: [u8; 40],
+    descriptor_set: [u8; MS_OS_20_DESCRIPTOR_SET_LEN as usize],
+}
+
+impl MicrosoftDescriptors {
+    /// Create descriptors for the CMSIS-DAP v2 interface `interface`.
+    pub fn new(interface: InterfaceNumber) -> Self {
+        let interface = u8::from(interface);
+
+        let mut compatible_id = MS_COMPATIBLE_ID_DESCRIPTOR_TEMPLATE;
+        compatible_id[16] = interface;
+
+        let mut descriptor_set = MS_OS_20_DESCRIPTOR_SET_TEMPLATE;
+        descriptor_set[MS_OS_20_FIRST_INTERFACE_OFFSET] = interface;
+
+        MicrosoftDescriptors {
+            interface,
+            compatible_id,
+            descriptor_set,
+        }
+    }
+}
 
 impl<B: UsbBus> UsbClass<B> for MicrosoftDescriptors {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        writer.capability(capability_type::PLATFORM, &MS_OS_20_PLATFORM_CAPABILITY)
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
         if req.request_type != RequestType::Vendor {
             return;
         }
 
-        if req.request == GET_OS_FEATURE {
+        if req.request == MS_OS_20_VENDOR_CODE {
+            if req.index == MS_OS_20_DESCRIPTOR_INDEX {
+                xfer.accept_with(&self.descriptor_set).ok();
+            } else {
+                xfer.reject().ok();
+            }
+        } else if req.request == GET_OS_FEATURE {
             match OSFeatureDescriptorType::try_from(req.index) {
                 Ok(OSFeatureDescriptorType::CompatibleID) => {
                     // Handle request for an Extended Compatible ID Descriptor.
                     // Interface number is ignored as there is only one device-wide
                     // Compatible ID Descriptor.
-                    xfer.accept_with_static(&MS_COMPATIBLE_ID_DESCRIPTOR).ok();
+                    xfer.accept_with(&self.compatible_id).ok();
                 }
                 Ok(OSFeatureDescriptorType::Properties) => {
                     // Handle request for an Extended Properties OS Descriptor.
-                    match req.value as u8 {
-                        2 => {
-                            xfer.accept_with_static(&IF2_MS_PROPERTIES_OS_DESCRIPTOR)
-                                .ok();
-                        }
-                        _ => {
-                            xfer.reject().ok();
-                        }
+                    if req.value as u8 == self.interface {
+                        xfer.accept_with_static(&MS_PROPERTIES_OS_DESCRIPTOR).ok();
+                    } else {
+                        xfer.reject().ok();
                     }
                 }
                 _ => {
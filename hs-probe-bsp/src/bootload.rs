@@ -1,26 +1,46 @@
 // Copyright 2019 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
-use stm32ral::{modify_reg, scb};
+use crate::flash::Flash;
+use crate::image::{ImageHeader, Slot};
+use core::sync::atomic::{AtomicBool, Ordering};
+use stm32ral::{iwdg, modify_reg, read_reg, scb, write_reg};
 
-static mut FLAG: u32 = 0;
+// Flags shared between the boot stage and the firmware live in the top 16 bytes
+// of RAM. Both are linked with RAM shortened to exclude them, so neither
+// initialises them at startup and they survive a system reset.
+const BOOTLOAD_FLAG: *mut u32 = 0x2003_FFF0 as *mut u32;
+const TRIAL_FLAG: *mut u32 = 0x2003_FFF4 as *mut u32;
 const FLAG_VALUE: u32 = 0xB00110AD;
+const TRIAL_VALUE: u32 = 0x7E57_B000;
 
-/// Call this function at boot in pre_init, before statics are initialised.
+const SYSTEM_MEMORY: u32 = 0x0010_0000;
+
+/// Watchdog reload value for the boot confirmation window.
+///
+/// With the LSI at a nominal 32kHz and a /256 prescaler this is around 32s.
+const CONFIRM_WINDOW_RELOAD: u32 = 0xFFF;
+const IWDG_PRESCALER_DIV256: u32 = 0b110;
+
+/// Set once the running image has confirmed itself, after which the watchdog
+/// is fed from a periodic interrupt rather than only from the main loop.
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
+
+/// Call this function at boot in the boot stage.
 ///
 /// If we reset due to requesting a bootload, this function will jump to
 /// the system bootloader.
 pub fn check() {
     unsafe {
         // If flag isn't set we just continue with the boot process
-        if core::ptr::read_volatile(&FLAG) != FLAG_VALUE {
+        if core::ptr::read_volatile(BOOTLOAD_FLAG) != FLAG_VALUE {
             return;
         }
 
         // Otherwise, clear the flag and jump to system bootloader
-        core::ptr::write_volatile(&mut FLAG, 0);
+        core::ptr::write_volatile(BOOTLOAD_FLAG, 0);
 
-        cortex_m::asm::bootload(0x0010_0000 as *const u32);
+        cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32);
     }
 }
 
@@ -28,7 +48,7 @@ pub fn check() {
 pub fn bootload() -> ! {
     unsafe {
         // Write flag value to FLAG
-        core::ptr::write_volatile(&mut FLAG, FLAG_VALUE);
+        core::ptr::write_volatile(BOOTLOAD_FLAG, FLAG_VALUE);
 
         // Request system reset
         modify_reg!(scb, SCB, AIRCR, VECTKEYSTAT: 0x05FA, SYSRESETREQ: 1);
@@ -39,3 +59,105 @@ pub fn bootload() -> ! {
         cortex_m::asm::nop();
     }
 }
+
+/// Call this function from the boot stage after `check()` to start the firmware.
+///
+/// Starts the newest image which has a valid CRC and has not been rejected.
+/// An image which has not yet confirmed itself is started on a trial basis
+/// with the watchdog running; if we find we were reset during its trial, it
+/// is rejected and the next newest image is started instead.
+///
+/// If neither slot holds a valid image, an unheadered binary in slot A is
+/// started as-is, which allows loading development builds with a debugger.
+/// Failing that, we jump to the system bootloader to allow recovery.
+pub fn boot(flash: &Flash) -> ! {
+    let trial = unsafe { core::ptr::read_volatile(TRIAL_FLAG) };
+    unsafe { core::ptr::write_volatile(TRIAL_FLAG, 0) };
+
+    let mut slots = [
+        (Slot::A, Slot::A.valid_header()),
+        (Slot::B, Slot::B.valid_header()),
+    ];
+    let version = |header: Option<ImageHeader>| header.map(|h| h.version);
+    if version(slots[1].1) > version(slots[0].1) {
+        slots.swap(0, 1);
+    }
+
+    for &(slot, header) in slots.iter() {
+        if header.is_none() || slot.is_rejected() {
+            continue;
+        }
+
+        if !slot.is_confirmed() {
+            if trial == TRIAL_VALUE | slot as u32 {
+                // We were reset before this image confirmed itself
+                slot.reject(flash).ok();
+                continue;
+            }
+
+            unsafe { core::ptr::write_volatile(TRIAL_FLAG, TRIAL_VALUE | slot as u32) };
+            start_watchdog();
+        }
+
+        unsafe { start(slot.binary_address()) };
+    }
+
+    if slots.iter().all(|(_, header)| header.is_none()) && has_vector_table(Slot::A) {
+        unsafe { start(Slot::A.binary_address()) };
+    }
+
+    unsafe { cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32) }
+}
+
+/// Call this function once the firmware is running normally.
+///
+/// Marks the running image as good so the boot stage keeps starting it.
+pub fn confirm(flash: &Flash) {
+    unsafe { core::ptr::write_volatile(TRIAL_FLAG, 0) };
+    CONFIRMED.store(true, Ordering::SeqCst);
+
+    if let Some(slot) = Slot::current() {
+        if slot.header().is_some() && !slot.is_confirmed() {
+            slot.confirm(flash).ok();
+        }
+    }
+}
+
+/// Reload the boot confirmation watchdog.
+///
+/// The watchdog cannot be stopped once started for a trial boot,
+/// so the firmware must call this regularly.
+pub fn feed_watchdog() {
+    write_reg!(iwdg, IWDG, KR, 0xAAAA);
+}
+
+/// Reload the watchdog if the running image has confirmed itself.
+///
+/// Call this from a periodic interrupt, so that once the trial is over long
+/// blocking operations such as flash erases do not reset the probe.
+pub fn feed_watchdog_if_confirmed() {
+    if CONFIRMED.load(Ordering::SeqCst) {
+        feed_watchdog();
+    }
+}
+
+fn start_watchdog() {
+    write_reg!(iwdg, IWDG, KR, 0xCCCC);
+    write_reg!(iwdg, IWDG, KR, 0x5555);
+    write_reg!(iwdg, IWDG, PR, IWDG_PRESCALER_DIV256);
+    write_reg!(iwdg, IWDG, RLR, CONFIRM_WINDOW_RELOAD);
+    while read_reg!(iwdg, IWDG, SR) != 0 {}
+    write_reg!(iwdg, IWDG, KR, 0xAAAA);
+}
+
+/// Check the initial stack pointer in the slot's vector table points into RAM.
+fn has_vector_table(slot: Slot) -> bool {
+    let sp = unsafe { core::ptr::read_volatile(slot.binary_address() as *const u32) };
+    (0x2000_0000..=0x2004_0000).contains(&sp)
+}
+
+/// Jump to the firmware whose vector table is at `address`.
+unsafe fn start(address: u32) -> ! {
+    write_reg!(scb, SCB, VTOR, address);
+    cortex_m::asm::bootload(address as *const u32)
+}
@@ -0,0 +1,64 @@
+use stm32ral::crc;
+use stm32ral::{read_reg, write_reg};
+
+/// CRC-32 polynomial, in the normal (MSB-first) representation used by the CRC unit.
+const POLYNOMIAL: u32 = 0x04C1_1DB7;
+
+/// Compute the standard (IEEE 802.3, as used by zlib) CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Update a running CRC-32 with `data`.
+///
+/// Start with `crc = 0xFFFF_FFFF` and invert the final result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// The CRC calculation unit, computing the same CRC-32 as `crc32` in hardware.
+pub struct CRC {
+    crc: crc::Instance,
+}
+
+impl CRC {
+    pub fn new(crc: crc::Instance) -> Self {
+        CRC { crc }
+    }
+
+    /// Start a new CRC-32 calculation.
+    pub fn reset(&self) {
+        write_reg!(crc, self.crc, INIT, 0xFFFF_FFFF);
+        write_reg!(crc, self.crc, POL, POLYNOMIAL);
+        // Reflect input bytes and the output, as for the software implementation
+        write_reg!(crc, self.crc, CR, REV_OUT: 1, REV_IN: 0b01, POLYSIZE: 0b00, RESET: 1);
+    }
+
+    /// Add `data` to the calculation started by `reset`.
+    pub fn update(&self, data: &[u8]) {
+        let words = data.chunks_exact(4);
+        let tail = words.remainder();
+        for word in words {
+            // Input is reflected per byte, so the first byte goes in the top bits
+            let word = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            write_reg!(crc, self.crc, DR, word);
+        }
+        let dr = &self.crc.DR as *const _ as *mut u8;
+        for &byte in tail {
+            // Byte writes to DR add just that byte
+            unsafe { core::ptr::write_volatile(dr, byte) };
+        }
+    }
+
+    /// Return the CRC-32 of all data since `reset`.
+    pub fn finish(&self) -> u32 {
+        !read_reg!(crc, self.crc, DR)
+    }
+}
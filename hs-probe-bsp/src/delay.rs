@@ -4,6 +4,7 @@ use stm32ral::syst;
 use stm32ral::{modify_reg, read_reg, write_reg};
 
 const SYST_CSR_ENABLE: u32 = 1 << 0;
+const SYST_CSR_TICKINT: u32 = 1 << 1;
 const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
 
 pub struct Delay {
@@ -29,6 +30,13 @@ impl Delay {
         }
     }
 
+    /// Generate a SysTick exception each time the counter wraps.
+    ///
+    /// This periodically wakes the core when it is sleeping in WFI.
+    pub fn enable_wrap_interrupt(&self) {
+        modify_reg!(syst, self.systick, CSR, |r| (r | SYST_CSR_TICKINT));
+    }
+
     pub fn set_sysclk(&self, clocks: &Clocks) {
         self.base_clock.store(clocks.hclk(), Ordering::SeqCst);
     }
@@ -43,6 +51,20 @@ impl Delay {
         self.delay_ticks(ticks as u32);
     }
 
+    /// Delay for any number of microseconds, in steps short enough for `delay_us`
+    pub fn delay_long_us(&self, mut us: u32) {
+        while us > 0 {
+            let step = core::cmp::min(us, 9_999);
+            self.delay_us(step);
+            us -= step;
+        }
+    }
+
+    /// Current SysTick clock frequency
+    pub fn base_clock(&self) -> u32 {
+        self.base_clock.load(Ordering::SeqCst)
+    }
+
     pub fn calc_period_ticks(&self, frequency: u32) -> u32 {
         let base_clock = self.base_clock.load(Ordering::SeqCst);
         assert!(base_clock > 0);
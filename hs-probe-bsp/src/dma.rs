@@ -85,6 +85,7 @@ impl DMA {
             PINC: Fixed,
             CIRC: Disabled,
             DIR: PeripheralToMemory,
+            TCIE: Enabled,
             EN: Disabled
         );
         write_reg!(
@@ -129,6 +130,8 @@ impl DMA {
             PINC: Fixed,
             CIRC: Enabled,
             DIR: PeripheralToMemory,
+            HTIE: Enabled,
+            TCIE: Enabled,
             EN: Disabled
         );
         write_reg!(
@@ -260,6 +263,11 @@ impl DMA {
         modify_reg!(dma, self.dma1, CR4, EN: Disabled);
     }
 
+    /// Clear SPI2 RX transfer complete interrupt flag
+    pub fn spi2_clear_interrupts(&self) {
+        write_reg!(dma, self.dma1, LIFCR, CTCIF3: Clear);
+    }
+
     /// Start USART1 reception into provided buffer
     pub fn usart1_start(&self, rx: &mut [u8]) {
         write_reg!(
@@ -272,9 +280,14 @@ impl DMA {
             CDMEIF5: Clear,
             CFEIF5: Clear
         );
-        write_reg!(dma, self.dma1, NDTR5, rx.len() as u32);
-        write_reg!(dma, self.dma1, M0AR5, rx.as_mut_ptr() as u32);
-        modify_reg!(dma, self.dma1, CR5, EN: Enabled);
+        write_reg!(dma, self.dma2, NDTR5, rx.len() as u32);
+        write_reg!(dma, self.dma2, M0AR5, rx.as_mut_ptr() as u32);
+        modify_reg!(dma, self.dma2, CR5, EN: Enabled);
+    }
+
+    /// Clear USART1 half and full transfer interrupt flags
+    pub fn usart1_clear_interrupts(&self) {
+        write_reg!(dma, self.dma2, HIFCR, CTCIF5: Clear, CHTIF5: Clear);
     }
 
     /// Return how many bytes are left to transfer for USART1
@@ -0,0 +1,179 @@
+use stm32ral::flash;
+use stm32ral::{modify_reg, read_reg, write_reg};
+
+/// Start address of each flash sector, followed by the end address of flash.
+pub const SECTOR_ADDRESSES: [u32; 9] = [
+    0x0800_0000,
+    0x0800_4000,
+    0x0800_8000,
+    0x0800_C000,
+    0x0801_0000,
+    0x0802_0000,
+    0x0804_0000,
+    0x0806_0000,
+    0x0808_0000,
+];
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// PSIZE value for 32-bit parallelism, valid for VDD between 2.7V and 3.6V.
+const PSIZE_X32: u32 = 0b10;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// Address is outside of flash or not word aligned.
+    InvalidAddress,
+    WriteProtection,
+    Alignment,
+    Parallelism,
+    Erase,
+    Operation,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+pub struct Flash {
+    flash: flash::Instance,
+}
+
+impl Flash {
+    pub fn new(flash: flash::Instance) -> Self {
+        Flash { flash }
+    }
+
+    /// Returns the sector number containing `address`, if any.
+    pub fn sector(address: u32) -> Option<u8> {
+        SECTOR_ADDRESSES
+            .windows(2)
+            .position(|w| address >= w[0] && address < w[1])
+            .map(|n| n as u8)
+    }
+
+    /// Returns the size in bytes of `sector`.
+    pub fn sector_size(sector: u8) -> u32 {
+        let sector = sector as usize;
+        SECTOR_ADDRESSES[sector + 1] - SECTOR_ADDRESSES[sector]
+    }
+
+    /// Erase a single flash sector.
+    ///
+    /// This blocks for the duration of the erase, which is up to 2s
+    /// for the 128kB sectors.
+    pub fn erase_sector(&self, sector: u8) -> Result<()> {
+        if sector as usize >= SECTOR_ADDRESSES.len() - 1 {
+            return Err(Error::InvalidAddress);
+        }
+
+        self.unlock();
+        self.clear_errors();
+        modify_reg!(
+            flash,
+            self.flash,
+            CR,
+            PG: 0,
+            SER: 1,
+            SNB: sector as u32,
+            PSIZE: PSIZE_X32
+        );
+        modify_reg!(flash, self.flash, CR, STRT: 1);
+        let result = self.wait();
+        modify_reg!(flash, self.flash, CR, SER: 0);
+        self.lock();
+
+        result
+    }
+
+    /// Program `data` into flash starting at `address`.
+    ///
+    /// `address` must be word aligned and the target area must have been erased.
+    /// If `data` is not a multiple of four bytes long, the final word is padded with 0xFF.
+    pub fn program(&self, address: u32, data: &[u8]) -> Result<()> {
+        let end = address + data.len() as u32;
+        if address & 0b11 != 0
+            || address < SECTOR_ADDRESSES[0]
+            || end > SECTOR_ADDRESSES[SECTOR_ADDRESSES.len() - 1]
+        {
+            return Err(Error::InvalidAddress);
+        }
+
+        self.unlock();
+        self.clear_errors();
+        modify_reg!(flash, self.flash, CR, SER: 0, PG: 1, PSIZE: PSIZE_X32);
+
+        let mut result = Ok(());
+        for (idx, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0xFF; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let word = u32::from_le_bytes(word);
+            let dst = (address + 4 * idx as u32) as *mut u32;
+            unsafe { core::ptr::write_volatile(dst, word) };
+            cortex_m::asm::dsb();
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        modify_reg!(flash, self.flash, CR, PG: 0);
+        self.lock();
+
+        result
+    }
+
+    /// Read `len` bytes of flash starting at `address`.
+    pub fn read(address: u32, len: usize) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+    }
+
+    fn unlock(&self) {
+        if read_reg!(flash, self.flash, CR, LOCK) != 0 {
+            write_reg!(flash, self.flash, KEYR, KEY1);
+            write_reg!(flash, self.flash, KEYR, KEY2);
+        }
+    }
+
+    fn lock(&self) {
+        modify_reg!(flash, self.flash, CR, LOCK: 1);
+    }
+
+    fn clear_errors(&self) {
+        write_reg!(
+            flash,
+            self.flash,
+            SR,
+            EOP: 1,
+            OPERR: 1,
+            WRPERR: 1,
+            PGAERR: 1,
+            PGPERR: 1,
+            ERSERR: 1
+        );
+    }
+
+    /// Wait for the current operation to finish and report any error flags.
+    fn wait(&self) -> Result<()> {
+        while read_reg!(flash, self.flash, SR, BSY) != 0 {}
+
+        let (operr, wrperr, pgaerr, pgperr, erserr) =
+            read_reg!(flash, self.flash, SR, OPERR, WRPERR, PGAERR, PGPERR, ERSERR);
+        let result = if wrperr != 0 {
+            Err(Error::WriteProtection)
+        } else if pgaerr != 0 {
+            Err(Error::Alignment)
+        } else if pgperr != 0 {
+            Err(Error::Parallelism)
+        } else if erserr != 0 {
+            Err(Error::Erase)
+        } else if operr != 0 {
+            Err(Error::Operation)
+        } else {
+            Ok(())
+        };
+
+        if result.is_err() {
+            self.clear_errors();
+        }
+        result
+    }
+}
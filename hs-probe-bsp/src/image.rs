@@ -0,0 +1,150 @@
+//! Firmware image layout for A/B updates.
+//!
+//! Flash sector 0 holds a small boot stage, and sectors 5 and 6 hold two
+//! application slots. Each slot contains a `HEADER_SIZE` byte header block
+//! followed by a firmware binary linked to run from that slot.
+//!
+//! The boot stage starts the newest valid image. A new image must confirm
+//! itself once running, otherwise the boot stage rejects it and falls back
+//! to the image in the other slot.
+
+use crate::crc::crc32;
+use crate::flash::{self, Flash};
+use core::convert::TryInto;
+use stm32ral::{read_reg, scb};
+
+/// Size of the header block preceding the firmware binary.
+pub const HEADER_SIZE: u32 = 0x200;
+
+/// Size of each application slot, including the header block.
+pub const SLOT_SIZE: u32 = 128 * 1024;
+
+const HEADER_MAGIC: u32 = 0x4950_5348; // "HSPI"
+
+// Status words inside the header block. They are left erased when an image is
+// written and are later programmed to zero, which needs no further erase.
+const CONFIRMED_OFFSET: u32 = 0x100;
+const REJECTED_OFFSET: u32 = 0x104;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Slot {
+    A = 0xA,
+    B = 0xB,
+}
+
+impl Slot {
+    /// Start address of the slot, where its header block is stored.
+    pub fn address(self) -> u32 {
+        match self {
+            Slot::A => 0x0802_0000,
+            Slot::B => 0x0804_0000,
+        }
+    }
+
+    /// Flash sector containing the slot.
+    pub fn sector(self) -> u8 {
+        match self {
+            Slot::A => 5,
+            Slot::B => 6,
+        }
+    }
+
+    /// Address the firmware binary in this slot is linked to run from.
+    pub fn binary_address(self) -> u32 {
+        self.address() + HEADER_SIZE
+    }
+
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// Returns the slot the running firmware was started from.
+    ///
+    /// Returns None when not running from either slot, for example in the boot stage.
+    pub fn current() -> Option<Slot> {
+        let vtor = read_reg!(scb, SCB, VTOR);
+        [Slot::A, Slot::B]
+            .iter()
+            .copied()
+            .find(|slot| slot.binary_address() == vtor)
+    }
+
+    /// Read the image header stored in this slot.
+    ///
+    /// Returns None if there is no header, or it is for an image linked for the other slot.
+    pub fn header(self) -> Option<ImageHeader> {
+        ImageHeader::parse(Flash::read(self.address(), HEADER_SIZE as usize))
+            .filter(|header| header.load_address == self.binary_address())
+    }
+
+    /// Read the image header stored in this slot, if the image's CRC matches.
+    pub fn valid_header(self) -> Option<ImageHeader> {
+        self.header().filter(|header| header.verify(self))
+    }
+
+    pub fn is_confirmed(self) -> bool {
+        self.status_word(CONFIRMED_OFFSET) == 0
+    }
+
+    pub fn is_rejected(self) -> bool {
+        self.status_word(REJECTED_OFFSET) == 0
+    }
+
+    /// Mark the image in this slot as having booted successfully.
+    pub fn confirm(self, flash: &Flash) -> flash::Result<()> {
+        flash.program(self.address() + CONFIRMED_OFFSET, &[0; 4])
+    }
+
+    /// Mark the image in this slot as having failed to boot.
+    pub fn reject(self, flash: &Flash) -> flash::Result<()> {
+        flash.program(self.address() + REJECTED_OFFSET, &[0; 4])
+    }
+
+    fn status_word(self, offset: u32) -> u32 {
+        let word = Flash::read(self.address() + offset, 4);
+        u32::from_le_bytes(word.try_into().unwrap())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ImageHeader {
+    pub version: u32,
+    pub length: u32,
+    pub crc: u32,
+    pub load_address: u32,
+}
+
+impl ImageHeader {
+    /// Parse an image header from the start of `data`.
+    ///
+    /// Returns None if the magic number is missing or the length is implausible.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 20 {
+            return None;
+        }
+        let word = |idx: usize| u32::from_le_bytes(data[idx..idx + 4].try_into().unwrap());
+        if word(0) != HEADER_MAGIC {
+            return None;
+        }
+        let header = ImageHeader {
+            version: word(4),
+            length: word(8),
+            crc: word(12),
+            load_address: word(16),
+        };
+        if header.length == 0 || header.length > SLOT_SIZE - HEADER_SIZE {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Check the CRC of the firmware binary following this header in `slot`.
+    pub fn verify(&self, slot: Slot) -> bool {
+        let binary = Flash::read(slot.binary_address(), self.length as usize);
+        crc32(binary) == self.crc
+    }
+}
@@ -1,10 +1,14 @@
 #![no_std]
 
 pub mod bootload;
+pub mod crc;
 pub mod delay;
 pub mod dma;
+pub mod flash;
 pub mod gpio;
+pub mod image;
 pub mod otg_hs;
 pub mod rcc;
 pub mod spi;
+pub mod timer;
 pub mod uart;
@@ -48,56 +48,89 @@ impl RCC {
         // Wait for HSE to be ready
         while read_reg!(rcc, self.rcc, CR, HSERDY == NotReady) {}
 
-        // Calculate prescalers
-        let ppre1;
-        let ppre2;
-        match frequency {
-            CoreFrequency::F48MHz => {
-                ppre1 = 0b000; // AHB clock not divided
-                ppre2 = 0b000; // AHB clock not divided
-            }
-            CoreFrequency::F72MHz => {
-                ppre1 = 0b100; // AHB clock divided by 2
-                ppre2 = 0b000; // AHB clock not divided
-            }
-            CoreFrequency::F216MHz => {
-                ppre1 = 0b101; // AHB clock divided by 4
-                ppre2 = 0b100; // AHB clock divided by 2
-            }
+        // Enable PWR domain for voltage scaling
+        modify_reg!(rcc, self.rcc, APB1ENR, PWREN: Enabled);
+
+        let clocks = self.set_frequency(frequency);
+
+        // Enable peripheral clocks
+        modify_reg!(
+            rcc,
+            self.rcc,
+            AHB1ENR,
+            GPIOAEN: Enabled,
+            GPIOBEN: Enabled,
+            GPIOCEN: Enabled,
+            GPIODEN: Enabled,
+            GPIOEEN: Enabled,
+            GPIOGEN: Enabled,
+            GPIOIEN: Enabled,
+            DMA1EN: Enabled,
+            DMA2EN: Enabled,
+            CRCEN: Enabled
+        );
+        modify_reg!(rcc, self.rcc, APB1ENR, SPI2EN: Enabled, USART2EN: Enabled, TIM2EN: Enabled);
+        modify_reg!(rcc, self.rcc, APB2ENR, SPI1EN: Enabled, USART1EN: Enabled);
+
+        clocks
+    }
+
+    /// Change the core frequency, leaving peripheral clocks enabled.
+    ///
+    /// Unsafety: this function should be called from the main context.
+    /// No other contexts should be active at the same time.
+    pub unsafe fn set_frequency(&self, frequency: CoreFrequency) -> Clocks {
+        self.configure(frequency.into())
+    }
+
+    /// Change only the APB prescalers to those of `config`, which must share
+    /// the PLL configuration currently running (see `ClockConfig::same_pll`).
+    ///
+    /// The system clock keeps running from the PLL, so this is safe while USB is
+    /// in use. Peripherals whose timing depends on the bus clocks must be
+    /// updated from the returned `Clocks` afterwards.
+    pub fn set_prescalers(&self, config: ClockConfig) -> Clocks {
+        modify_reg!(
+            rcc,
+            self.rcc,
+            CFGR,
+            PPRE1: ppre_bits(config.ppre1),
+            PPRE2: ppre_bits(config.ppre2)
+        );
+        Clocks {
+            sysclk: config.sysclk(),
         }
+    }
+
+    /// Change the clock tree configuration, leaving peripheral clocks enabled.
+    ///
+    /// The system clock runs from HSE while the PLL is reconfigured, too slow
+    /// for USB HS, so this must not be used while USB transfers may be running. Peripherals
+    /// whose timing depends on the bus clocks must be updated from the returned
+    /// `Clocks` afterwards. The 48 MHz PLLQ output used by USB is kept.
+    ///
+    /// Unsafety: this function should be called from the main context.
+    /// No other contexts should be active at the same time.
+    pub unsafe fn configure(&self, config: ClockConfig) -> Clocks {
+        // Swap system clock to HSE and stop the PLL
+        modify_reg!(rcc, self.rcc, CFGR, SW: HSE);
+        while read_reg!(rcc, self.rcc, CFGR, SWS != HSE) {}
+        modify_reg!(rcc, self.rcc, CR, PLLON: Off);
+        while read_reg!(rcc, self.rcc, CR, PLLRDY == Ready) {}
+
+        // Leave over-drive mode, it is enabled again below if required
+        let pwr = &*pwr::PWR;
+        modify_reg!(pwr, pwr, CR1, ODSWEN: 0, ODEN: 0);
+
         // Set prescalers
-        modify_reg!(rcc, self.rcc, CFGR, HPRE: Div1, PPRE1: ppre1, PPRE2: ppre2);
-
-        // Calculate PLL parameters and flash latency
-        let pllm = 6;
-        let plln;
-        let pllp;
-        let pllq;
-        let flash_latency;
-        let sysclk;
-        match frequency {
-            CoreFrequency::F48MHz => {
-                plln = 96;
-                pllp = 0b01; // /4
-                pllq = 4;
-                flash_latency = 0b0001;
-                sysclk = 48_000_000;
-            }
-            CoreFrequency::F72MHz => {
-                plln = 144;
-                pllp = 0b01; // /4
-                pllq = 6;
-                flash_latency = 0b0010;
-                sysclk = 72_000_000;
-            }
-            CoreFrequency::F216MHz => {
-                plln = 216;
-                pllp = 0b00; // /2
-                pllq = 9;
-                flash_latency = 0b0111;
-                sysclk = 216_000_000;
-            }
-        }
+        modify_reg!(
+            rcc,
+            self.rcc,
+            CFGR,
+            HPRE: Div1,
+            PPRE1: ppre_bits(config.ppre1),
+            PPRE2: ppre_bits(config.ppre2)
+        );
 
         // Configure PLL from HSE
         modify_reg!(
@@ -105,20 +138,18 @@ impl RCC {
             self.rcc,
             PLLCFGR,
             PLLSRC: HSE,
-            PLLM: pllm,
-            PLLN: plln,
-            PLLP: pllp,
-            PLLQ: pllq
+            PLLM: PLLM,
+            PLLN: config.vco / PLL_INPUT,
+            PLLP: config.pllp / 2 - 1,
+            PLLQ: config.vco / USB_CLOCK
         );
 
-        // Enable PWR domain and setup voltage scale and overdrive options
-        modify_reg!(rcc, self.rcc, APB1ENR, PWREN: Enabled);
-
+        // Setup voltage scale and overdrive options
+        let sysclk = config.sysclk();
         let enable_overdrive;
 
         // The scale can be modified only when the PLL is OFF and the
         // HSI or HSE clock source is selected as system clock source.
-        let pwr = &*pwr::PWR;
         if sysclk <= 144_000_000 {
             modify_reg!(pwr, pwr, CR1, VOS: SCALE3);
             enable_overdrive = false;
@@ -149,7 +180,9 @@ impl RCC {
         // Wait for PLL to be ready
         while read_reg!(rcc, self.rcc, CR, PLLRDY == NotReady) {}
 
-        // Adjust flash wait states
+        // Adjust flash wait states for 2.7-3.6V operation, one per 30 MHz.
+        // Any setting is safe while running from HSE.
+        let flash_latency = (sysclk - 1) / 30_000_000;
         modify_reg!(flash, &*flash::FLASH, ACR, LATENCY: flash_latency);
 
         // Swap system clock to PLL
@@ -157,35 +190,150 @@ impl RCC {
         // Wait for system clock to be PLL
         while read_reg!(rcc, self.rcc, CFGR, SWS != PLL) {}
 
-        // Enable peripheral clocks
-        modify_reg!(
-            rcc,
-            self.rcc,
-            AHB1ENR,
-            GPIOAEN: Enabled,
-            GPIOBEN: Enabled,
-            GPIOCEN: Enabled,
-            GPIODEN: Enabled,
-            GPIOEEN: Enabled,
-            GPIOGEN: Enabled,
-            GPIOIEN: Enabled,
-            DMA1EN: Enabled,
-            DMA2EN: Enabled
-        );
-        modify_reg!(rcc, self.rcc, APB1ENR, SPI2EN: Enabled, USART2EN: Enabled);
-        modify_reg!(rcc, self.rcc, APB2ENR, SPI1EN: Enabled, USART1EN: Enabled);
-
         Clocks { sysclk }
     }
 }
 
-#[derive(Eq, PartialEq)]
+/// HSE frequency, supplied in bypass mode
+const HSE_CLOCK: u32 = 12_000_000;
+/// PLL input divider
+const PLLM: u32 = 6;
+/// PLL input frequency
+const PLL_INPUT: u32 = HSE_CLOCK / PLLM;
+/// PLLQ output frequency, required by USB
+const USB_CLOCK: u32 = 48_000_000;
+
+/// USB OTG HS requires HCLK above 30 MHz
+const MIN_SYSCLK: u32 = 32_000_000;
+const MAX_SYSCLK: u32 = 216_000_000;
+const MAX_PCLK1: u32 = 54_000_000;
+const MAX_PCLK2: u32 = 108_000_000;
+
+/// Convert an APB division factor to PPREx register bits
+fn ppre_bits(div: u32) -> u32 {
+    match div {
+        2 => 0b100,
+        4 => 0b101,
+        8 => 0b110,
+        16 => 0b111,
+        _ => 0b000,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum CoreFrequency {
     F48MHz,
     F72MHz,
     F216MHz,
 }
 
+impl CoreFrequency {
+    /// Look up a core frequency by its value in MHz
+    pub fn from_mhz(mhz: u32) -> Option<Self> {
+        match mhz {
+            48 => Some(CoreFrequency::F48MHz),
+            72 => Some(CoreFrequency::F72MHz),
+            216 => Some(CoreFrequency::F216MHz),
+            _ => None,
+        }
+    }
+
+    pub fn mhz(self) -> u32 {
+        match self {
+            CoreFrequency::F48MHz => 48,
+            CoreFrequency::F72MHz => 72,
+            CoreFrequency::F216MHz => 216,
+        }
+    }
+}
+
+/// A clock tree configuration: PLL output and APB prescalers.
+///
+/// The VCO always runs at a multiple of 48 MHz so the PLLQ output keeps
+/// clocking USB whichever configuration is chosen.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ClockConfig {
+    vco: u32,
+    pllp: u32,
+    ppre1: u32,
+    ppre2: u32,
+}
+
+impl ClockConfig {
+    /// Create a configuration from a VCO frequency and PLLP divider,
+    /// using the smallest APB prescalers within the bus limits.
+    fn new(vco: u32, pllp: u32) -> Self {
+        let sysclk = vco / pllp;
+        let mut ppre1 = 1;
+        while sysclk / ppre1 > MAX_PCLK1 {
+            ppre1 *= 2;
+        }
+        let mut ppre2 = 1;
+        while sysclk / ppre2 > MAX_PCLK2 {
+            ppre2 *= 2;
+        }
+        ClockConfig {
+            vco,
+            pllp,
+            ppre1,
+            ppre2,
+        }
+    }
+
+    /// All supported configurations.
+    pub fn candidates() -> impl Iterator<Item = ClockConfig> {
+        // VCO from 144 MHz to 432 MHz, with PLLP of 2, 4, 6 or 8
+        (3..=9)
+            .flat_map(|n| (1..=4).map(move |p| ClockConfig::new(n * USB_CLOCK, 2 * p)))
+            .filter(|c| c.sysclk() >= MIN_SYSCLK && c.sysclk() <= MAX_SYSCLK)
+    }
+
+    /// Configurations sharing this one's PLL output, with each APB prescaler
+    /// from the smallest allowed up to 16, smallest first.
+    pub fn prescaler_variants(&self) -> impl Iterator<Item = ClockConfig> {
+        let base = ClockConfig::new(self.vco, self.pllp);
+        let ppre = |min: u32| (0..5).map(|n| 1 << n).filter(move |&div| div >= min);
+        // APB1 timers count microseconds, so need a whole number of MHz
+        let sysclk = base.sysclk();
+        let timer_mhz = move |&ppre1: &u32| ppre1 == 1 || (2 * sysclk / ppre1) % 1_000_000 == 0;
+        ppre(base.ppre1).filter(timer_mhz).flat_map(move |ppre1| {
+            ppre(base.ppre2).map(move |ppre2| ClockConfig {
+                ppre1,
+                ppre2,
+                ..base
+            })
+        })
+    }
+
+    /// Whether switching between this and `other` only changes APB prescalers,
+    /// keeping the system clock running from the PLL.
+    pub fn same_pll(&self, other: &ClockConfig) -> bool {
+        self.vco == other.vco && self.pllp == other.pllp
+    }
+
+    pub fn sysclk(&self) -> u32 {
+        self.vco / self.pllp
+    }
+
+    pub fn pclk1(&self) -> u32 {
+        self.sysclk() / self.ppre1
+    }
+
+    pub fn pclk2(&self) -> u32 {
+        self.sysclk() / self.ppre2
+    }
+}
+
+impl From<CoreFrequency> for ClockConfig {
+    fn from(frequency: CoreFrequency) -> Self {
+        match frequency {
+            CoreFrequency::F48MHz => ClockConfig::new(192_000_000, 4),
+            CoreFrequency::F72MHz => ClockConfig::new(288_000_000, 4),
+            CoreFrequency::F216MHz => ClockConfig::new(432_000_000, 2),
+        }
+    }
+}
+
 pub struct Clocks {
     sysclk: u32,
 }
@@ -28,6 +28,45 @@ pub enum SPIPrescaler {
     Div256 = 0b111,
 }
 
+/// SCK edge on which the SPI samples received data in SWD mode
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SampleEdge {
+    /// Sample on the rising edge, a full period after the target changes SWDIO
+    Rising,
+    /// Sample on the falling edge, half a period after the target changes SWDIO,
+    /// so earlier than `Rising`
+    Falling,
+}
+
+impl SPIPrescaler {
+    /// Find the fastest prescaler giving at most `max_frequency` from `base_clock`.
+    pub fn for_frequency(base_clock: u32, max_frequency: u32) -> Option<Self> {
+        const PRESCALERS: [SPIPrescaler; 8] = [
+            SPIPrescaler::Div2,
+            SPIPrescaler::Div4,
+            SPIPrescaler::Div8,
+            SPIPrescaler::Div16,
+            SPIPrescaler::Div32,
+            SPIPrescaler::Div64,
+            SPIPrescaler::Div128,
+            SPIPrescaler::Div256,
+        ];
+
+        if base_clock == 0 {
+            return None;
+        }
+        PRESCALERS
+            .iter()
+            .copied()
+            .find(|p| base_clock / p.divisor() <= max_frequency)
+    }
+
+    /// Division factor applied to the SPI base clock
+    pub fn divisor(self) -> u32 {
+        2 << (self as u32)
+    }
+}
+
 impl SPI {
     pub fn new(spi: spi::Instance) -> Self {
         SPI {
@@ -96,37 +135,26 @@ impl SPI {
         );
     }
 
+    /// Current SPI base clock, which is divided by the prescaler
+    pub fn base_clock(&self) -> u32 {
+        self.base_clock.load(Ordering::SeqCst)
+    }
+
     pub fn calculate_prescaler(&self, max_frequency: u32) -> Option<SPIPrescaler> {
-        let base_clock = self.base_clock.load(Ordering::SeqCst);
-        if base_clock == 0 {
-            return None;
-        }
+        SPIPrescaler::for_frequency(self.base_clock(), max_frequency)
+    }
 
-        if (base_clock / 2) <= max_frequency {
-            return Some(SPIPrescaler::Div2);
-        }
-        if (base_clock / 4) <= max_frequency {
-            return Some(SPIPrescaler::Div4);
-        }
-        if (base_clock / 8) <= max_frequency {
-            return Some(SPIPrescaler::Div8);
-        }
-        if (base_clock / 16) <= max_frequency {
-            return Some(SPIPrescaler::Div16);
-        }
-        if (base_clock / 32) <= max_frequency {
-            return Some(SPIPrescaler::Div32);
-        }
-        if (base_clock / 64) <= max_frequency {
-            return Some(SPIPrescaler::Div64);
-        }
-        if (base_clock / 128) <= max_frequency {
-            return Some(SPIPrescaler::Div128);
-        }
-        if (base_clock / 256) <= max_frequency {
-            return Some(SPIPrescaler::Div256);
+    /// Select the edge used to sample received data in SWD mode.
+    ///
+    /// The SPI is briefly disabled, so SCK should be held by GPIO meanwhile.
+    pub fn set_swd_sample_edge(&self, edge: SampleEdge) {
+        self.wait_busy();
+        modify_reg!(spi, self.spi, CR1, SPE: Disabled);
+        match edge {
+            SampleEdge::Rising => modify_reg!(spi, self.spi, CR1, CPHA: SecondEdge),
+            SampleEdge::Falling => modify_reg!(spi, self.spi, CR1, CPHA: FirstEdge),
         }
-        None
+        modify_reg!(spi, self.spi, CR1, SPE: Enabled);
     }
 
     /// Change SPI clock rate to one of the SPIClock variants
@@ -147,14 +175,21 @@ impl SPI {
         // Set up DMA transfer (configures NDTR and MAR and enables streams)
         dma.spi2_enable(txdata, &mut rxdata[..txdata.len()]);
 
-        // Start SPI transfer
-        modify_reg!(spi, self.spi, CR1, SPE: Enabled);
-
-        // Busy wait for RX DMA completion (at most 43µs)
-        while dma.spi2_busy() {}
-
-        // Disable DMA
-        dma.spi2_disable();
+        // Sleep until the RX DMA completion interrupt (at most 43µs). WFI inside
+        // the critical section still wakes on it, and clearing its flag before
+        // the critical section ends leaves the handler nothing to do.
+        cortex_m::interrupt::free(|_| {
+            // Start SPI transfer
+            modify_reg!(spi, self.spi, CR1, SPE: Enabled);
+
+            while dma.spi2_busy() {
+                cortex_m::asm::wfi();
+            }
+
+            // Disable DMA
+            dma.spi2_disable();
+            dma.spi2_clear_interrupts();
+        });
     }
 
     /// Transmit 4 bits
@@ -192,6 +227,26 @@ impl SPI {
         self.write_dr_u8(parity & 1);
     }
 
+    /// Transmit `txdata` as 8-bit frames using DMA, discarding received data.
+    ///
+    /// Blocks until the final frame has been clocked out.
+    pub fn swd_tx_dma(&self, dma: &DMA, txdata: &[u8]) {
+        let mut rxdata = [0u8; 8];
+        debug_assert!(txdata.len() <= rxdata.len());
+
+        // RX DMA must be enabled before TX DMA, which starts the transfer
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit, RXDMAEN: Enabled);
+        dma.spi1_enable(txdata, &mut rxdata[..txdata.len()]);
+        modify_reg!(spi, self.spi, CR2, TXDMAEN: Enabled);
+
+        // Wait for RX DMA completion, after which the SPI is idle
+        while dma.spi1_busy() {}
+        self.wait_busy();
+
+        dma.spi1_disable();
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
+    }
+
     /// Receive 4 bits
     pub fn rx4(&self) -> u8 {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FourBit);
@@ -213,7 +268,14 @@ impl SPI {
     /// This method requires `Pins` be passed in so it can directly control
     /// the SWD lines at the end of RDATA in order to correctly sample PARITY
     /// and then resume driving SWDIO.
-    pub fn swd_rdata_phase(&self, pins: &Pins) -> (u32, u8) {
+    ///
+    /// PARITY is sampled `parity_delay` CPU cycles after the final data bit,
+    /// to allow for slow level shifters or long cables.
+    ///
+    /// When every bit is received a clock late, `late` is set and the final
+    /// data bit is sampled on the turnaround clock. PARITY is then the bit still
+    /// arriving after it, and no further clock is sent before driving SWDIO.
+    pub fn swd_rdata_phase(&self, pins: &Pins, parity_delay: u32, late: bool) -> (u32, u8) {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
         // Trigger 4 words, filling the FIFO
         self.write_dr_u16(0);
@@ -232,16 +294,24 @@ impl SPI {
         // The parity bit is currently being driven onto the bus by the target.
         // On the next rising edge, the target will release the bus, and we need
         // to then start driving it before sending any more clocks to avoid a false START.
+        if parity_delay > 0 {
+            cortex_m::asm::delay(parity_delay);
+        }
         let parity = pins.spi1_miso.is_high() as u8;
-        // Take direct control of SWCLK
-        pins.swd_clk_direct();
-        // Send one clock pulse. Target releases bus after rising edge.
-        pins.spi1_clk.set_low();
-        pins.spi1_clk.set_high();
-        // Drive bus ourselves with 0 (all our SPI read transactions transmitted 0s)
-        pins.swd_tx();
-        // Restore SWCLK to SPI control
-        pins.swd_clk_spi();
+        if late {
+            // The turnaround clock has been sent, so drive the bus straight away
+            pins.swd_tx();
+        } else {
+            // Take direct control of SWCLK
+            pins.swd_clk_direct();
+            // Send one clock pulse. Target releases bus after rising edge.
+            pins.spi1_clk.set_low();
+            pins.spi1_clk.set_high();
+            // Drive bus ourselves with 0 (all our SPI read transactions transmitted 0s)
+            pins.swd_tx();
+            // Restore SWCLK to SPI control
+            pins.swd_clk_spi();
+        }
 
         // Trigger four dummy idle cycles
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FourBit);
@@ -0,0 +1,44 @@
+use crate::rcc::Clocks;
+use stm32ral::tim2;
+use stm32ral::{modify_reg, read_reg, write_reg};
+
+/// TIM2 counting microseconds through its full 32 bits.
+///
+/// Unlike SysTick it keeps counting while the core is busy, so it is used
+/// to timestamp and pace background work.
+pub struct Timer {
+    tim: tim2::Instance,
+}
+
+impl Timer {
+    pub fn new(tim: tim2::Instance) -> Self {
+        Timer { tim }
+    }
+
+    /// Start counting from zero.
+    pub fn setup(&self, clocks: &Clocks) {
+        write_reg!(tim2, self.tim, ARR, 0xFFFF_FFFF);
+        write_reg!(tim2, self.tim, CNT, 0);
+        self.set_base_clock(clocks);
+        modify_reg!(tim2, self.tim, CR1, CEN: Enabled);
+    }
+
+    /// Keep counting microseconds after the APB1 clock changes.
+    pub fn set_base_clock(&self, clocks: &Clocks) {
+        // APB1 timers run at twice PCLK1 whenever it is divided from HCLK
+        let pclk1 = clocks.pclk1();
+        let clock = if pclk1 == clocks.hclk() { pclk1 } else { 2 * pclk1 };
+        write_reg!(tim2, self.tim, PSC, clock / 1_000_000 - 1);
+
+        // Load the new prescaler now rather than at the next overflow,
+        // putting back the count which the update event clears
+        let now = self.now_us();
+        write_reg!(tim2, self.tim, EGR, UG: 1);
+        write_reg!(tim2, self.tim, CNT, now);
+    }
+
+    /// Microseconds since `setup`, wrapping after about 71 minutes.
+    pub fn now_us(&self) -> u32 {
+        read_reg!(tim2, self.tim, CNT)
+    }
+}
@@ -1,44 +1,60 @@
 // Copyright 2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
+use core::cell::UnsafeCell;
 use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicU32, AtomicUsize};
 use stm32ral::usart;
 use stm32ral::{modify_reg, read_reg, write_reg};
 
 use super::dma::DMA;
+use crate::rcc::Clocks;
+
+const BUFFER_LEN: usize = 256;
 
 pub struct UART<'a> {
     uart: usart::Instance,
     dma: &'a DMA,
-    buffer: [u8; 256],
-    last_idx: usize,
+    buffer: UnsafeCell<[u8; BUFFER_LEN]>,
+    last_idx: AtomicUsize,
+    base_clock: AtomicU32,
+    baud: AtomicU32,
 }
 
+// The buffer is only written by DMA and only read by `read()`, which must not
+// be called from two contexts at once, so it's safe to share the UART object.
+unsafe impl Sync for UART<'_> {}
+
 impl<'a> UART<'a> {
     pub fn new(uart: usart::Instance, dma: &'a DMA) -> Self {
         UART {
             uart,
             dma,
-            buffer: [0; 256],
-            last_idx: 0,
+            buffer: UnsafeCell::new([0; BUFFER_LEN]),
+            last_idx: AtomicUsize::new(0),
+            base_clock: AtomicU32::new(0),
+            baud: AtomicU32::new(0),
         }
     }
 
     /// Begin UART reception into buffer.
     ///
-    /// UART::poll must be called regularly after starting.
-    pub fn start(&mut self) {
-        self.last_idx = 0;
+    /// UART::read must be called regularly after starting. The USART1 interrupt
+    /// fires when the line goes idle, and the DMA stream interrupt fires when
+    /// each half of the buffer fills, so reading can be driven by interrupts.
+    pub fn start(&self) {
+        self.last_idx.store(0, atomic::Ordering::SeqCst);
         write_reg!(usart, self.uart, CR3, DMAR: Enabled);
         write_reg!(
             usart,
             self.uart,
             CR1,
             OVER8: Oversampling8,
+            IDLEIE: Enabled,
             RE: Enabled,
             UE: Enabled
         );
-        self.dma.usart1_start(&mut self.buffer);
+        self.dma.usart1_start(unsafe { &mut *self.buffer.get() });
     }
 
     /// End UART reception.
@@ -52,37 +68,63 @@ impl<'a> UART<'a> {
         read_reg!(usart, self.uart, CR1, RE == Enabled)
     }
 
+    /// Clear pending USART idle line and DMA stream interrupts.
+    pub fn clear_interrupts(&self) {
+        write_reg!(usart, self.uart, ICR, IDLECF: 1);
+        self.dma.usart1_clear_interrupts();
+    }
+
     /// Return length of internal buffer
     pub fn buffer_len(&self) -> usize {
-        self.buffer.len()
+        BUFFER_LEN
+    }
+
+    /// Set the UART kernel clock, reapplying the requested baud rate if any.
+    pub fn set_base_clock(&self, clocks: &Clocks) {
+        self.base_clock.store(clocks.pclk2(), atomic::Ordering::SeqCst);
+        let baud = self.baud.load(atomic::Ordering::SeqCst);
+        if baud != 0 {
+            self.set_baud(baud);
+        }
     }
 
     /// Request a target baud rate. Returns actual baud rate set.
     pub fn set_baud(&self, baud: u32) -> u32 {
+        self.baud.store(baud, atomic::Ordering::SeqCst);
+
+        // With 8x oversampling, the divider is twice the kernel clock over the baud rate
+        let clock = 2 * self.base_clock.load(atomic::Ordering::SeqCst);
+
         // Find closest divider which is also an even integer >= 16
-        let mut div = 96_000_000 / baud;
+        let mut div = clock / baud;
         div &= 0xffff_fffe;
         if div < 16 {
             div = 16;
         }
 
-        // Write BRR value based on div
+        // Write BRR value based on div, which requires the UART be disabled
+        let enabled = read_reg!(usart, self.uart, CR1, UE == Enabled);
+        modify_reg!(usart, self.uart, CR1, UE: Disabled);
         let brr = (div & 0xffff_fff0) | ((div & 0xf) >> 1);
         write_reg!(usart, self.uart, BRR, brr);
+        if enabled {
+            modify_reg!(usart, self.uart, CR1, UE: Enabled);
+        }
 
         // Return actual baud rate
-        96_000_000 / div
+        clock / div
     }
 
     /// Fetch current number of bytes available.
     ///
     /// Subsequent calls to read() may return a different amount of data.
     pub fn bytes_available(&self) -> usize {
-        let dma_idx = self.buffer.len() - self.dma.usart1_ndtr();
-        if dma_idx >= self.last_idx {
-            dma_idx - self.last_idx
+        let dma_idx = BUFFER_LEN - self.dma.usart1_ndtr();
+        let last_idx = self.last_idx.load(atomic::Ordering::SeqCst);
+        if dma_idx >= last_idx {
+            dma_idx - last_idx
         } else {
-            (self.buffer.len() - self.last_idx) + dma_idx
+            (BUFFER_LEN - last_idx) + dma_idx
         }
     }
 
@@ -93,14 +135,16 @@ impl<'a> UART<'a> {
     /// Reads at most rx.len() new bytes, which may be less than what was received.
     /// Remaining data will be read on the next call, so long as the internal buffer
     /// doesn't overflow, which is not detected.
-    pub fn read(&mut self, rx: &mut [u8]) -> usize {
+    pub fn read(&self, rx: &mut [u8]) -> usize {
         // See what index the DMA is going to write next, and copy out
         // all prior data. Even if the DMA writes new data while we're
         // processing we won't get out of sync and will handle the new
         // data next time read() is called.
-        let dma_idx = self.buffer.len() - self.dma.usart1_ndtr();
+        let dma_idx = BUFFER_LEN - self.dma.usart1_ndtr();
+        let last_idx = self.last_idx.load(atomic::Ordering::SeqCst);
+        let buffer = unsafe { &*self.buffer.get() };
 
-        match dma_idx.cmp(&self.last_idx) {
+        match dma_idx.cmp(&last_idx) {
             Ordering::Equal => {
                 // No action required if no data has been received.
                 0
@@ -108,7 +152,7 @@ impl<'a> UART<'a> {
             Ordering::Less => {
                 // Wraparound occurred:
                 // Copy from last_idx to end, and from start to new dma_idx.
-                let mut n1 = self.buffer.len() - self.last_idx;
+                let mut n1 = BUFFER_LEN - last_idx;
                 let mut n2 = dma_idx;
                 let mut new_last_idx = dma_idx;
 
@@ -116,31 +160,31 @@ impl<'a> UART<'a> {
                 if n1 > rx.len() {
                     n1 = rx.len();
                     n2 = 0;
-                    new_last_idx = self.last_idx + n1;
+                    new_last_idx = last_idx + n1;
                 } else if (n1 + n2) > rx.len() {
                     n2 = rx.len() - n1;
                     new_last_idx = n2;
                 }
 
-                rx[..n1].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n1]);
-                rx[n1..(n1 + n2)].copy_from_slice(&self.buffer[..n2]);
+                rx[..n1].copy_from_slice(&buffer[last_idx..last_idx + n1]);
+                rx[n1..(n1 + n2)].copy_from_slice(&buffer[..n2]);
 
-                self.last_idx = new_last_idx;
+                self.last_idx.store(new_last_idx, atomic::Ordering::SeqCst);
                 n1 + n2
             }
             Ordering::Greater => {
                 // New data, no wraparound:
                 // Copy from last_idx to new dma_idx.
-                let mut n = dma_idx - self.last_idx;
+                let mut n = dma_idx - last_idx;
 
                 // Ensure we don't overflow rx buffer
                 if n > rx.len() {
                     n = rx.len();
                 }
 
-                rx[..n].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n]);
+                rx[..n].copy_from_slice(&buffer[last_idx..last_idx + n]);
 
-                self.last_idx += n;
+                self.last_idx.store(last_idx + n, atomic::Ordering::SeqCst);
                 n
             }
         }
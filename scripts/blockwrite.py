@@ -0,0 +1,148 @@
+#!/usr/bin/env python3
+"""
+Measure SWD block write throughput of an HS-Probe over its CMSIS-DAP v2 interface.
+
+Usage: blockwrite.py [--clocks HZ,HZ,...] [--address ADDR] [--seconds S]
+
+The probe must be connected to a target with a MEM-AP at index 0 and writable
+memory at the given address. The same word is written repeatedly with
+DAP_TransferBlock packets of 126 words, so each clock is measured end to end,
+including USB turnaround, and printed as a Markdown table row.
+"""
+
+import argparse
+import struct
+import time
+
+import usb.core
+import usb.util
+
+DEFAULT_VID = 0x1209
+DEFAULT_PID = 0x4853
+PACKET_SIZE = 512
+
+DAP_CONNECT = 0x02
+DAP_DISCONNECT = 0x03
+DAP_TRANSFER = 0x05
+DAP_TRANSFER_BLOCK = 0x06
+DAP_SWJ_CLOCK = 0x11
+DAP_SWJ_SEQUENCE = 0x12
+
+# Transfer requests: APnDP in bit 0, RnW in bit 1, A[3:2] in bits 2-3
+DP_READ_IDCODE = 0x02
+DP_WRITE_CTRL_STAT = 0x04
+DP_READ_CTRL_STAT = 0x06
+AP_WRITE_CSW = 0x01
+AP_WRITE_TAR = 0x05
+AP_WRITE_DRW = 0x0D
+
+# 32-bit accesses without address increment
+CSW_VALUE = 0x23000002
+POWER_UP = 0x50000000
+WORDS_PER_PACKET = (PACKET_SIZE - 5) // 4
+
+
+class Probe:
+    def __init__(self, vid, pid):
+        self.dev = usb.core.find(idVendor=vid, idProduct=pid)
+        if self.dev is None:
+            raise SystemExit("HS-Probe not found")
+        cfg = self.dev.get_active_configuration()
+        for intf in cfg:
+            if intf.bInterfaceClass == 0xFF and intf.bNumEndpoints >= 2:
+                break
+        else:
+            raise SystemExit("CMSIS-DAP v2 interface not found")
+        usb.util.claim_interface(self.dev, intf)
+        self.ep_out = usb.util.find_descriptor(
+            intf,
+            custom_match=lambda e: usb.util.endpoint_direction(e.bEndpointAddress)
+            == usb.util.ENDPOINT_OUT,
+        )
+        self.ep_in = usb.util.find_descriptor(
+            intf,
+            custom_match=lambda e: usb.util.endpoint_direction(e.bEndpointAddress)
+            == usb.util.ENDPOINT_IN,
+        )
+
+    def command(self, data):
+        self.ep_out.write(bytes(data))
+        resp = bytes(self.ep_in.read(PACKET_SIZE, timeout=1000))
+        if resp[0] != data[0]:
+            raise SystemExit(f"unexpected response to 0x{data[0]:02x}: {resp.hex()}")
+        return resp
+
+    def transfer(self, *requests):
+        data = [DAP_TRANSFER, 0, len(requests)]
+        for req, value in requests:
+            data.append(req)
+            if value is not None:
+                data += struct.pack("<I", value)
+        resp = self.command(data)
+        if resp[1] != len(requests) or resp[2] != 1:
+            raise SystemExit(f"transfer failed: {resp[:3].hex()}")
+        return resp[3:]
+
+    def connect(self, clock):
+        self.command([DAP_SWJ_CLOCK] + list(struct.pack("<I", clock)))
+        if self.command([DAP_CONNECT, 1])[1] != 1:
+            raise SystemExit("SWD connect failed")
+        # Line reset, JTAG-to-SWD, line reset, idle
+        self.command([DAP_SWJ_SEQUENCE, 56] + [0xFF] * 7)
+        self.command([DAP_SWJ_SEQUENCE, 16, 0x9E, 0xE7])
+        self.command([DAP_SWJ_SEQUENCE, 56] + [0xFF] * 7)
+        self.command([DAP_SWJ_SEQUENCE, 8, 0x00])
+        self.transfer((DP_READ_IDCODE, None))
+        self.transfer((DP_WRITE_CTRL_STAT, POWER_UP))
+        self.transfer((DP_READ_CTRL_STAT, None))
+
+    def disconnect(self):
+        self.command([DAP_DISCONNECT])
+
+
+def measure(probe, clock, address, seconds):
+    probe.connect(clock)
+    probe.transfer((AP_WRITE_CSW, CSW_VALUE), (AP_WRITE_TAR, address))
+
+    packet = bytes([DAP_TRANSFER_BLOCK, 0]) + struct.pack("<HB", WORDS_PER_PACKET, AP_WRITE_DRW)
+    packet += struct.pack("<I", 0xA5A5A5A5) * WORDS_PER_PACKET
+
+    words = 0
+    start = time.perf_counter()
+    while time.perf_counter() - start < seconds:
+        resp = probe.command(packet)
+        count, ack = struct.unpack("<HB", resp[1:4])
+        if count != WORDS_PER_PACKET or ack != 1:
+            raise SystemExit(f"block write failed at {clock} Hz: {resp[:4].hex()}")
+        words += count
+    elapsed = time.perf_counter() - start
+
+    probe.disconnect()
+    return words / elapsed
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.strip().splitlines()[0])
+    parser.add_argument(
+        "--clocks",
+        default="36000000,18000000,9000000,4500000,1125000",
+        help="comma-separated SWJ clocks to measure in Hz",
+    )
+    parser.add_argument(
+        "--address", type=lambda s: int(s, 0), default=0x20000000, help="target RAM address"
+    )
+    parser.add_argument("--seconds", type=float, default=2.0, help="duration of each run")
+    parser.add_argument("--vid", type=lambda s: int(s, 0), default=DEFAULT_VID)
+    parser.add_argument("--pid", type=lambda s: int(s, 0), default=DEFAULT_PID)
+    args = parser.parse_args()
+
+    probe = Probe(args.vid, args.pid)
+    print("| SWCLK requested | Writes/s | Throughput |")
+    print("|-----------------|----------|------------|")
+    for clock in (int(c) for c in args.clocks.split(",")):
+        rate = measure(probe, clock, args.address, args.seconds)
+        print(f"| {clock / 1e6:g} MHz | {rate / 1e3:.1f} k | {rate * 4 / 1e3:.0f} kB/s |")
+
+
+if __name__ == "__main__":
+    main()
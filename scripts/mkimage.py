@@ -0,0 +1,63 @@
+#!/usr/bin/env python3
+"""
+Wrap a raw firmware binary in the image header expected by the
+HS-Probe DFU interface.
+
+Usage: mkimage.py firmware.bin firmware.img [--version N] [--slot a|b]
+
+The version defaults to the number of commits in the current git branch.
+The slot must match the one the binary was linked for (`--features slot-b`).
+"""
+
+import argparse
+import struct
+import subprocess
+import zlib
+
+HEADER_MAGIC = 0x49505348  # "HSPI"
+HEADER_SIZE = 0x200
+SLOT_SIZE = 128 * 1024
+SLOT_ADDRESSES = {"a": 0x08020000, "b": 0x08040000}
+
+
+def git_version():
+    out = subprocess.check_output(["git", "rev-list", "--count", "HEAD"])
+    return int(out.strip())
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.strip().splitlines()[0])
+    parser.add_argument("binary", help="raw firmware binary from cargo objcopy")
+    parser.add_argument("image", help="output image for dfu-util")
+    parser.add_argument("--version", type=int, help="image version number")
+    parser.add_argument(
+        "--slot", choices=SLOT_ADDRESSES, default="a", help="slot the binary is linked for"
+    )
+    args = parser.parse_args()
+
+    with open(args.binary, "rb") as f:
+        binary = f.read()
+
+    if len(binary) > SLOT_SIZE - HEADER_SIZE:
+        raise SystemExit(
+            f"{args.binary}: {len(binary)} bytes does not fit in a slot, "
+            f"which holds {SLOT_SIZE - HEADER_SIZE} bytes"
+        )
+
+    version = args.version if args.version is not None else git_version()
+    crc = zlib.crc32(binary) & 0xFFFFFFFF
+    load_address = SLOT_ADDRESSES[args.slot] + HEADER_SIZE
+    header = struct.pack("<IIIII", HEADER_MAGIC, version, len(binary), crc, load_address)
+    header = header.ljust(HEADER_SIZE, b"\xff")
+
+    with open(args.image, "wb") as f:
+        f.write(header + binary)
+
+    print(
+        f"{args.image}: slot {args.slot.upper()}, version {version}, "
+        f"{len(binary)} bytes, CRC {crc:08x}"
+    )
+
+
+if __name__ == "__main__":
+    main()